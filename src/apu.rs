@@ -1,7 +1,6 @@
 use core::mem::size_of;
 
-use crate::memory::CPUBus;
-use crate::mos6502::CPU_FREQ;
+use crate::region::Region;
 use crate::utils::Sampler;
 use crate::utils::{load_prefix, save_prefix, Read, Write};
 
@@ -88,7 +87,6 @@ pub trait Speaker {
     fn queue(&mut self, sample: i16);
 }
 
-const QUARTER_FRAME_FREQ: u32 = 240;
 pub const AUDIO_SAMPLE_FREQ: u32 = 44100;
 
 const TRI_SEQ_TABLE: [u8; 32] = [
@@ -101,6 +99,18 @@ const LEN_TABLE: [u8; 32] = [
     18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
 ];
 
+/* CPU cycles from the (delayed) $4017 write to each step of the frame
+ * sequencer, indexed by [5-step mode][step]; the last step wraps around */
+const FRAME_STEPS: [[u16; 6]; 2] = [
+    [7457, 14913, 22371, 29828, 29829, 29830],
+    [7457, 14913, 22371, 29829, 37281, 37282],
+];
+
+const FRAME_STEPS_PAL: [[u16; 6]; 2] = [
+    [8313, 16627, 24939, 33252, 33253, 33254],
+    [8313, 16627, 24939, 33253, 41565, 41566],
+];
+
 const DUTY_TABLE: [u8; 4] = [0b00000010, 0b00000110, 0b00011110, 0b11111001];
 
 const PULSE_TABLE: [u16; 31] = [
@@ -114,10 +124,18 @@ const NOISE_PERIOD_TABLE: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
 ];
 
+const NOISE_PERIOD_TABLE_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
 const DMC_TABLE: [u16; 16] = [
     214, 190, 170, 160, 143, 127, 113, 107, 95, 80, 71, 64, 53, 42, 36, 27,
 ];
 
+const DMC_TABLE_PAL: [u16; 16] = [
+    199, 177, 158, 149, 138, 118, 105, 99, 88, 74, 66, 59, 49, 39, 33, 25,
+];
+
 const TND_TABLE: [u16; 203] = [
     0x0000, 0x01b7, 0x036a, 0x051a, 0x06c6, 0x086f, 0x0a15, 0x0bb7, 0x0d56,
     0x0ef2, 0x108a, 0x121f, 0x13b1, 0x1540, 0x16cc, 0x1855, 0x19da, 0x1b5d,
@@ -144,6 +162,32 @@ const TND_TABLE: [u16; 203] = [
     0xbbfe, 0xbc84, 0xbd09, 0xbd8d, 0xbe11,
 ];
 
+/* a copy of the visible state of a channel for debuggers and tests */
+#[derive(Copy, Clone)]
+pub struct ChannelState {
+    pub enabled: bool,
+    pub length: u16, /* length counter, remaining bytes for the DMC */
+    pub timer_period: u16,
+    pub timer: u16,
+    pub output: u8,
+}
+
+#[derive(Copy, Clone)]
+pub struct ApuState {
+    pub pulse1: ChannelState,
+    pub pulse2: ChannelState,
+    pub triangle: ChannelState,
+    pub noise: ChannelState,
+    pub dmc: ChannelState,
+    pub dmc_addr: u16, /* address of the next sample byte */
+    pub dmc_irq_enabled: bool,
+    pub dmc_irq: bool,
+    pub frame_step: u8,
+    pub frame_mode: bool, /* true for 5-step mode */
+    pub frame_irq_inhibit: bool,
+    pub frame_irq: bool,
+}
+
 #[repr(C)]
 pub struct Pulse {
     /* envelope */
@@ -334,6 +378,16 @@ impl Pulse {
         self.enabled = true
     }
 
+    fn get_state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.enabled,
+            length: self.get_len() as u16,
+            timer_period: self.timer_period,
+            timer: self.timer_lvl,
+            output: self.output(),
+        }
+    }
+
     #[inline(always)]
     fn get_len(&self) -> u8 {
         self.len_lvl
@@ -462,6 +516,16 @@ impl Triangle {
         self.enabled = true
     }
 
+    fn get_state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.enabled,
+            length: self.get_len() as u16,
+            timer_period: self.timer_period,
+            timer: self.timer_lvl,
+            output: self.output(),
+        }
+    }
+
     #[inline(always)]
     fn get_len(&self) -> u8 {
         self.len_lvl
@@ -495,6 +559,7 @@ pub struct Noise {
     loop_noise: bool,
     /* channel */
     enabled: bool,
+    pal: bool, /* use the PAL period table */
 }
 
 impl Noise {
@@ -513,6 +578,7 @@ impl Noise {
             shift_reg: 1,
             loop_noise: false,
             enabled: false,
+            pal: false,
         }
     }
 
@@ -533,7 +599,12 @@ impl Noise {
 
     pub fn write_reg3(&mut self, data: u8) {
         self.loop_noise = (data >> 7) == 1;
-        self.timer_period = NOISE_PERIOD_TABLE[data as usize & 0xf];
+        let table = if self.pal {
+            &NOISE_PERIOD_TABLE_PAL
+        } else {
+            &NOISE_PERIOD_TABLE
+        };
+        self.timer_period = table[data as usize & 0xf];
     }
 
     pub fn write_reg4(&mut self, data: u8) {
@@ -606,6 +677,16 @@ impl Noise {
         self.enabled = true
     }
 
+    fn get_state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.enabled,
+            length: self.get_len() as u16,
+            timer_period: self.timer_period,
+            timer: self.timer_lvl,
+            output: self.output(),
+        }
+    }
+
     #[inline(always)]
     fn get_len(&self) -> u8 {
         self.len_lvl
@@ -622,11 +703,16 @@ impl Noise {
 #[repr(C)]
 pub struct DMC {
     dmc_loop: bool,
-    dmc_cnt: u8,
+    dmc_cnt: u8, /* remaining bits in the output shift register */
     irq_enabled: bool,
+    irq: bool, /* interrupt flag, bit 7 of $4015 */
     sample_addr: u16,
     sample_len: u16,
     shift_reg: u8,
+    /* sample buffer, refilled by DMA on the CPU bus */
+    sample_buf: u8,
+    buf_empty: bool,
+    silence: bool,
     cur_addr: u16,
     rem_len: u16,
     level: u8,
@@ -635,6 +721,7 @@ pub struct DMC {
     timer_period: u16,
     /* channel */
     enabled: bool,
+    pal: bool, /* use the PAL rate table */
 }
 
 impl DMC {
@@ -643,15 +730,20 @@ impl DMC {
             dmc_loop: false,
             dmc_cnt: 8,
             irq_enabled: false,
+            irq: false,
             sample_addr: 0,
             sample_len: 0,
             shift_reg: 0,
+            sample_buf: 0,
+            buf_empty: true,
+            silence: true,
             cur_addr: 0,
             rem_len: 0,
             level: 0,
             timer_lvl: 0,
             timer_period: 0,
             enabled: false,
+            pal: false,
         }
     }
 
@@ -665,8 +757,12 @@ impl DMC {
 
     pub fn write_reg1(&mut self, data: u8) {
         self.irq_enabled = (data >> 7) == 1;
+        if !self.irq_enabled {
+            self.irq = false
+        }
         self.dmc_loop = data & 0x40 == 0x40;
-        self.timer_period = DMC_TABLE[(data & 0xf) as usize];
+        let table = if self.pal { &DMC_TABLE_PAL } else { &DMC_TABLE };
+        self.timer_period = table[(data & 0xf) as usize];
     }
 
     pub fn write_reg2(&mut self, data: u8) {
@@ -686,48 +782,64 @@ impl DMC {
         self.rem_len = self.sample_len;
     }
 
-    fn try_refill(&mut self, bus: &CPUBus) {
-        if self.rem_len > 0 && self.dmc_cnt == 0 {
-            bus.cpu_stall(4);
-            self.shift_reg = bus.get_cpu().mem.read_without_tick(self.cur_addr);
-            self.dmc_cnt = 8;
-            self.cur_addr = self.cur_addr.wrapping_add(1);
-            if self.cur_addr == 0x0 {
-                self.cur_addr = 0x8000
-            }
-            self.rem_len -= 1;
-            if self.rem_len == 0 {
-                if self.dmc_loop {
-                    self.restart()
-                } else if self.irq_enabled {
-                    bus.get_cpu().trigger_irq()
-                }
+    /* the memory reader asks for a DMA whenever the sample buffer is empty
+     * and there are bytes left to play */
+    #[inline(always)]
+    pub fn dma_pending(&self) -> bool {
+        self.buf_empty && self.rem_len > 0
+    }
+
+    #[inline(always)]
+    pub fn get_dma_addr(&self) -> u16 {
+        self.cur_addr
+    }
+
+    /* called by the CPU bus once the DMA has fetched the sample byte */
+    pub fn dma_fill(&mut self, data: u8) {
+        self.sample_buf = data;
+        self.buf_empty = false;
+        self.cur_addr = self.cur_addr.wrapping_add(1);
+        if self.cur_addr == 0x0 {
+            self.cur_addr = 0x8000
+        }
+        self.rem_len -= 1;
+        if self.rem_len == 0 {
+            if self.dmc_loop {
+                self.restart()
+            } else if self.irq_enabled {
+                self.irq = true
             }
         }
     }
 
     fn shift(&mut self) {
-        if self.dmc_cnt == 0 {
-            return
-        }
-        if self.shift_reg & 1 == 1 {
-            if self.level < 126 {
-                self.level += 2
-            }
-        } else {
-            if self.level > 1 {
-                self.level -= 2
+        if !self.silence {
+            if self.shift_reg & 1 == 1 {
+                if self.level < 126 {
+                    self.level += 2
+                }
+            } else {
+                if self.level > 1 {
+                    self.level -= 2
+                }
             }
         }
         self.shift_reg >>= 1;
         self.dmc_cnt -= 1;
+        if self.dmc_cnt == 0 {
+            /* start a new output cycle */
+            self.dmc_cnt = 8;
+            if self.buf_empty {
+                self.silence = true
+            } else {
+                self.silence = false;
+                self.shift_reg = self.sample_buf;
+                self.buf_empty = true;
+            }
+        }
     }
 
-    fn tick_timer(&mut self, bus: &CPUBus) {
-        if !self.enabled {
-            return
-        }
-        self.try_refill(bus);
+    fn tick_timer(&mut self) {
         if self.timer_lvl == 0 {
             self.timer_lvl = self.timer_period;
             self.shift();
@@ -736,6 +848,16 @@ impl DMC {
         }
     }
 
+    fn get_state(&self) -> ChannelState {
+        ChannelState {
+            enabled: self.enabled,
+            length: self.get_len(),
+            timer_period: self.timer_period,
+            timer: self.timer_lvl,
+            output: self.output(),
+        }
+    }
+
     #[inline(always)]
     fn get_len(&self) -> u16 {
         self.rem_len
@@ -764,10 +886,19 @@ impl DMC {
 #[repr(C)]
 pub struct APU<'a> {
     /*-- begin state --*/
-    frame_lvl: u8,
+    frame_cycle: u16, /* CPU cycles since the sequencer was reset */
+    frame_lvl: u8,    /* the next step of the sequencer */
     frame_mode: bool, /* true for 5-step mode */
     frame_inh: bool,
     frame_int: bool,
+    frame_pal: bool, /* use the PAL step timings */
+    /* a $4017 write resets the sequencer 3 or 4 cycles later */
+    frame_new: u8,
+    frame_delay: u8, /* cycles left before frame_new applies, 0 if none */
+    /* cycles left during which a frame clock is swallowed, so that the
+     * clock from a $4017 write and the one of the step right after it are
+     * not both applied */
+    frame_block: u8,
     cycle_even: bool,
     /*-- end state --*/
 
@@ -782,7 +913,6 @@ pub struct APU<'a> {
     hp_filter1: HPFilter,
     hp_filter2: HPFilter,
 
-    frame_sampler: Sampler,
     audio_sampler: Sampler,
     /*-- end sub-state --*/
     spkr: &'a mut dyn Speaker,
@@ -799,7 +929,6 @@ macro_rules! APU_IGNORED_SIZE {
             size_of::<HPFilter>() +
             size_of::<HPFilter>() +
             size_of::<Sampler>() +
-            size_of::<Sampler>() +
             size_of::<&dyn Speaker>()
     };
 }
@@ -812,12 +941,20 @@ impl<'a> APU<'a> {
             triangle: Triangle::new(),
             noise: Noise::new(),
             dmc: DMC::new(),
+            frame_cycle: 0,
             frame_lvl: 0,
             frame_mode: false,
             frame_int: false,
             frame_inh: true,
-            frame_sampler: Sampler::new(CPU_FREQ, QUARTER_FRAME_FREQ),
-            audio_sampler: Sampler::new(CPU_FREQ, AUDIO_SAMPLE_FREQ),
+            frame_pal: false,
+            /* at power-up, the sequencer starts as if $4017 was written */
+            frame_new: 0x40,
+            frame_delay: 3,
+            frame_block: 0,
+            audio_sampler: Sampler::new(
+                Region::NTSC.cpu_freq(),
+                AUDIO_SAMPLE_FREQ,
+            ),
             cycle_even: false,
             spkr,
             lp_filter: LPFilter::new(),
@@ -836,7 +973,6 @@ impl<'a> APU<'a> {
             self.lp_filter.load(reader) &&
             self.hp_filter1.load(reader) &&
             self.hp_filter2.load(reader) &&
-            self.frame_sampler.load(reader) &&
             self.audio_sampler.load(reader)
     }
 
@@ -850,22 +986,28 @@ impl<'a> APU<'a> {
             self.lp_filter.save(writer) &&
             self.hp_filter1.save(writer) &&
             self.hp_filter2.save(writer) &&
-            self.frame_sampler.save(writer) &&
             self.audio_sampler.save(writer)
     }
 
-    pub fn tick(&mut self, bus: &CPUBus) -> bool {
-        let mut irq = false;
-        if self.frame_sampler.tick() {
-            irq = self.tick_frame_counter();
-        }
+    /* switch the clock rate and the period tables; Dendy uses the NTSC
+     * tables */
+    pub fn set_region(&mut self, region: Region) {
+        self.audio_sampler = Sampler::new(region.cpu_freq(), AUDIO_SAMPLE_FREQ);
+        self.frame_pal = region == Region::PAL;
+        self.noise.pal = region == Region::PAL;
+        self.dmc.pal = region == Region::PAL;
+    }
+
+    /* run one CPU cycle, returns the level of the IRQ line */
+    pub fn tick(&mut self) -> bool {
+        self.tick_frame_counter();
         if self.audio_sampler.tick() {
             let sample = self.output();
             self.spkr.queue(sample);
         }
-        self.tick_timer(bus);
+        self.tick_timer();
         self.cycle_even = !self.cycle_even;
-        irq
+        self.frame_int || self.dmc.irq
     }
 
     pub fn output(&mut self) -> i16 {
@@ -883,16 +1025,35 @@ impl<'a> APU<'a> {
         )
     }
 
+    pub fn get_state(&self) -> ApuState {
+        ApuState {
+            pulse1: self.pulse1.get_state(),
+            pulse2: self.pulse2.get_state(),
+            triangle: self.triangle.get_state(),
+            noise: self.noise.get_state(),
+            dmc: self.dmc.get_state(),
+            dmc_addr: self.dmc.cur_addr,
+            dmc_irq_enabled: self.dmc.irq_enabled,
+            dmc_irq: self.dmc.irq,
+            frame_step: self.frame_lvl,
+            frame_mode: self.frame_mode,
+            frame_irq_inhibit: self.frame_inh,
+            frame_irq: self.frame_int,
+        }
+    }
+
     pub fn read_status(&mut self) -> u8 {
         let res = if self.pulse1.get_len() > 0 { 1 } else { 0 } |
             (if self.pulse2.get_len() > 0 { 1 } else { 0 }) << 1 |
             (if self.triangle.get_len() > 0 { 1 } else { 0 }) << 2 |
             (if self.noise.get_len() > 0 { 1 } else { 0 }) << 3 |
             (if self.dmc.get_len() > 0 { 1 } else { 0 }) << 4 |
-            (if self.frame_int { 1 } else { 0 }) << 6;
-        if self.frame_lvl != 3 {
-            self.frame_int = false; /* clear interrupt flag */
-        }
+            (if self.frame_int { 1 } else { 0 }) << 6 |
+            (if self.dmc.irq { 1 } else { 0 }) << 7;
+        /* reading clears the flag; a read on the first or second of the
+         * three cycles that raise it is followed by the sequencer raising
+         * it again */
+        self.frame_int = false;
         res
     }
 
@@ -917,23 +1078,40 @@ impl<'a> APU<'a> {
             0 => self.dmc.disable(),
             _ => self.dmc.enable(),
         }
+        self.dmc.irq = false
+    }
+
+    /* the reset button: all channels are silenced, the DMC keeps the low
+     * bit of its output level and the frame counter restarts in its last
+     * mode, as if $4017 was written again */
+    pub fn reset(&mut self) {
+        self.write_status(0);
+        self.dmc.level &= 1;
+        self.frame_int = false;
+        self.frame_new = (if self.frame_mode { 0x80 } else { 0 }) |
+            (if self.frame_inh { 0x40 } else { 0 });
+        self.frame_delay = 3;
+        self.frame_block = 0
     }
 
+    /* the IRQ inhibit takes effect at once, the mode switch and the
+     * sequencer reset 3 cycles after the write cycle if it falls on an APU
+     * cycle, 4 otherwise */
     pub fn write_frame_counter(&mut self, data: u8) {
         self.frame_inh = data & 0x40 == 0x40;
-        self.frame_mode = data >> 7 == 1;
-        if self.frame_mode {
-            self.tick_env_cnt();
-            self.tick_len_swp();
+        if self.frame_inh {
+            self.frame_int = false
         }
+        self.frame_new = data;
+        self.frame_delay = if self.cycle_even { 4 } else { 3 }
     }
 
-    fn tick_timer(&mut self, bus: &CPUBus) {
+    fn tick_timer(&mut self) {
         if self.cycle_even {
             self.pulse1.tick_timer();
             self.pulse2.tick_timer();
             self.noise.tick_timer();
-            self.dmc.tick_timer(bus);
+            self.dmc.tick_timer();
         }
         self.triangle.tick_timer();
     }
@@ -954,48 +1132,53 @@ impl<'a> APU<'a> {
         self.noise.tick_length();
     }
 
-    fn tick_frame_counter(&mut self) -> bool {
-        /*
-        println!("{} {} {} {} {} {} {} {} {} {} {} {}",
-                 self.pulse1.output(), self.pulse2.output(),
-                 self.pulse1.seq_wave, self.pulse2.seq_wave,
-                 self.pulse1.timer_period, self.pulse2.timer_period,
-                 self.pulse1.timer_lvl, self.pulse2.timer_lvl,
-                 self.pulse1.env_period, self.pulse2.env_period,
-                 self.pulse1.env_lvl, self.pulse2.env_lvl
-                 );
-                 */
-        let f = self.frame_lvl;
-        match self.frame_mode {
-            false => {
-                self.frame_lvl = if f == 3 { 0 } else { f + 1 };
-                match self.frame_lvl {
-                    1 | 3 => self.tick_env_cnt(),
-                    2 => {
-                        self.tick_env_cnt();
-                        self.tick_len_swp();
-                    }
-                    _ => {
-                        self.tick_env_cnt();
-                        self.tick_len_swp();
-                        if !self.frame_inh {
-                            self.frame_int = true;
-                        }
-                    }
-                };
+    fn tick_frame_counter(&mut self) {
+        let steps = if self.frame_pal {
+            &FRAME_STEPS_PAL
+        } else {
+            &FRAME_STEPS
+        };
+        let step = self.frame_lvl as usize;
+        self.frame_cycle += 1;
+        if self.frame_cycle == steps[self.frame_mode as usize][step] {
+            /* the 4-step mode raises the flag on its last three cycles */
+            if !self.frame_mode && step >= 3 && !self.frame_inh {
+                self.frame_int = true
             }
-            true => {
-                self.frame_lvl = if f == 4 { 0 } else { f + 1 };
-                match self.frame_lvl {
-                    1 | 3 => self.tick_env_cnt(),
-                    0 | 2 => {
-                        self.tick_env_cnt();
-                        self.tick_len_swp();
-                    }
-                    _ => (),
+            /* quarter frames on steps 0 and 2, half frames on 1 and 4 */
+            let half = step == 1 || step == 4;
+            if (half || step == 0 || step == 2) && self.frame_block == 0 {
+                self.tick_env_cnt();
+                if half {
+                    self.tick_len_swp()
                 }
+                self.frame_block = 2
+            }
+            if step == 5 {
+                self.frame_lvl = 0;
+                self.frame_cycle = 0
+            } else {
+                self.frame_lvl += 1
             }
         }
-        self.frame_int
+
+        if self.frame_delay > 0 {
+            self.frame_delay -= 1;
+            if self.frame_delay == 0 {
+                self.frame_mode = self.frame_new & 0x80 == 0x80;
+                self.frame_lvl = 0;
+                self.frame_cycle = 0;
+                /* the 5-step mode clocks all units at once */
+                if self.frame_mode && self.frame_block == 0 {
+                    self.tick_env_cnt();
+                    self.tick_len_swp();
+                    self.frame_block = 2
+                }
+            }
+        }
+
+        if self.frame_block > 0 {
+            self.frame_block -= 1
+        }
     }
 }
@@ -15,26 +15,14 @@ use runes::controller::{stdctl, InputPoller};
 use runes::mapper;
 use runes::memory::{CPUMemory, PPUMemory};
 use runes::mos6502;
+use runes::ntsc::{ntsc_width, NtscFilter};
+use runes::palette::{NtscParams, Palette};
 use runes::ppu;
+use runes::ppu::Overscan;
+use runes::region::Region;
+use runes::trigger::Trigger;
 use runes::utils;
 
-const RGB_COLORS: [u32; 64] = [
-    0x666666, 0x002a88, 0x1412a7, 0x3b00a4, 0x5c007e, 0x6e0040, 0x6c0600,
-    0x561d00, 0x333500, 0x0b4800, 0x005200, 0x004f08, 0x00404d, 0x000000,
-    0x000000, 0x000000, 0xadadad, 0x155fd9, 0x4240ff, 0x7527fe, 0xa01acc,
-    0xb71e7b, 0xb53120, 0x994e00, 0x6b6d00, 0x388700, 0x0c9300, 0x008f32,
-    0x007c8d, 0x000000, 0x000000, 0x000000, 0xfffeff, 0x64b0ff, 0x9290ff,
-    0xc676ff, 0xf36aff, 0xfe6ecc, 0xfe8170, 0xea9e22, 0xbcbe00, 0x88d800,
-    0x5ce430, 0x45e082, 0x48cdde, 0x4f4f4f, 0x000000, 0x000000, 0xfffeff,
-    0xc0dfff, 0xd3d2ff, 0xe8c8ff, 0xfbc2ff, 0xfec4ea, 0xfeccc5, 0xf7d8a5,
-    0xe4e594, 0xcfef96, 0xbdf4ab, 0xb3f3cc, 0xb5ebf2, 0xb8b8b8, 0x000000,
-    0x000000,
-];
-
-const PIX_WIDTH: u32 = 256;
-const PIX_HEIGHT: u32 = 240;
-const FB_PITCH: usize = PIX_WIDTH as usize * 3;
-const FB_SIZE: usize = PIX_HEIGHT as usize * FB_PITCH;
 const AUDIO_SAMPLES: u16 = 441;
 const AUDIO_EXTRA_SAMPLES: u16 = 4410;
 const AUDIO_ALL_SAMPLES: u16 = AUDIO_SAMPLES + AUDIO_EXTRA_SAMPLES;
@@ -43,6 +31,7 @@ pub struct SimpleCart {
     chr_rom: Vec<u8>,
     prg_rom: Vec<u8>,
     sram: Vec<u8>,
+    nt_ram: Vec<u8>,
     pub mirror_type: MirrorType,
 }
 
@@ -53,10 +42,15 @@ impl SimpleCart {
         sram: Vec<u8>,
         mirror_type: MirrorType,
     ) -> Self {
+        let nt_ram = match mirror_type {
+            MirrorType::Four => vec![0; 0x1000],
+            _ => Vec::new(),
+        };
         SimpleCart {
             chr_rom,
             prg_rom,
             sram,
+            nt_ram,
             mirror_type,
         }
     }
@@ -84,6 +78,7 @@ impl Cartridge for SimpleCart {
             BankType::PrgRom => self.prg_rom.len(),
             BankType::ChrRom => self.chr_rom.len(),
             BankType::Sram => self.sram.len(),
+            BankType::NtRam => self.nt_ram.len(),
         }
     }
     fn get_bank<'a>(
@@ -97,6 +92,7 @@ impl Cartridge for SimpleCart {
                 BankType::PrgRom => &self.prg_rom,
                 BankType::ChrRom => &self.chr_rom,
                 BankType::Sram => &self.sram,
+                BankType::NtRam => &self.nt_ram,
             })[base..base + size]) as *const [u8])
         }
     }
@@ -112,6 +108,7 @@ impl Cartridge for SimpleCart {
                 BankType::PrgRom => &mut self.prg_rom,
                 BankType::ChrRom => &mut self.chr_rom,
                 BankType::Sram => &mut self.sram,
+                BankType::NtRam => &mut self.nt_ram,
             })[base..base + size]) as *mut [u8])
         }
     }
@@ -126,12 +123,14 @@ impl Cartridge for SimpleCart {
     fn load(&mut self, reader: &mut dyn utils::Read) -> bool {
         self.load_sram(reader) &&
             SimpleCart::load_vec(&mut self.chr_rom, reader) &&
+            SimpleCart::load_vec(&mut self.nt_ram, reader) &&
             utils::load_prefix(&mut self.mirror_type, 0, reader)
     }
 
     fn save(&self, writer: &mut dyn utils::Write) -> bool {
         self.save_sram(writer) &&
             SimpleCart::save_vec(&self.chr_rom, writer) &&
+            SimpleCart::save_vec(&self.nt_ram, writer) &&
             utils::save_prefix(&self.mirror_type, 0, writer)
     }
 
@@ -168,6 +167,8 @@ struct SDLEventPoller {
     events: RefCell<sdl2::EventPump>,
     p1_button_state: Cell<u8>,
     exit_flag: Cell<bool>,
+    cheat_toggle: Cell<bool>,
+    reset_flag: Cell<bool>,
 }
 
 fn keyboard_mapping(code: sdl2::keyboard::Keycode) -> u8 {
@@ -211,6 +212,8 @@ impl SDLEventPoller {
             events: RefCell::new(_events),
             p1_button_state: Cell::new(0),
             exit_flag: Cell::new(false),
+            cheat_toggle: Cell::new(false),
+            reset_flag: Cell::new(false),
         }
     }
 
@@ -218,13 +221,23 @@ impl SDLEventPoller {
     fn is_exiting(&self) -> bool {
         self.exit_flag.get()
     }
+
+    #[inline]
+    fn take_cheat_toggle(&self) -> bool {
+        self.cheat_toggle.replace(false)
+    }
+
+    #[inline]
+    fn take_reset(&self) -> bool {
+        self.reset_flag.replace(false)
+    }
 }
 
 impl InputPoller for SDLEventPoller {
     #[inline]
     fn poll(&self) -> u8 {
         use sdl2::event::Event;
-        use sdl2::keyboard::Keycode::Escape;
+        use sdl2::keyboard::Keycode::{Escape, F2, F5};
         let mut ns = self.p1_button_state.get();
         for event in self.events.borrow_mut().poll_iter() {
             match event {
@@ -233,6 +246,16 @@ impl InputPoller for SDLEventPoller {
                     keycode: Some(Escape),
                     ..
                 } => self.exit_flag.set(true),
+                Event::KeyDown {
+                    keycode: Some(F5),
+                    repeat: false,
+                    ..
+                } => self.cheat_toggle.set(true),
+                Event::KeyDown {
+                    keycode: Some(F2),
+                    repeat: false,
+                    ..
+                } => self.reset_flag.set(true),
                 Event::KeyDown {
                     keycode: Some(c), ..
                 } => ns |= keyboard_mapping(c),
@@ -271,10 +294,16 @@ impl InputPoller for SDLEventPoller {
 
 struct SDLWindow<'a> {
     canvas: sdl2::render::WindowCanvas,
-    frame_buffer: [u8; FB_SIZE],
+    frame_buffer: Vec<u8>,
+    fb_pitch: usize,
     texture: sdl2::render::Texture,
-    copy_area: Option<sdl2::rect::Rect>,
+    overscan: Overscan,
     event: &'a SDLEventPoller,
+    palette: Palette,
+    ntsc: Option<Box<NtscFilter>>,
+    indices: Vec<u16>,
+    ntsc_out: Vec<u32>,
+    ntsc_phase: u8,
 }
 
 impl<'a> SDLWindow<'a> {
@@ -282,16 +311,21 @@ impl<'a> SDLWindow<'a> {
         video_subsystem: &sdl2::VideoSubsystem,
         event: &'a SDLEventPoller,
         pixel_scale: u32,
-        full_screen: bool,
+        overscan: Overscan,
+        palette: Palette,
+        ntsc: Option<NtscFilter>,
     ) -> Self {
-        let mut actual_height = PIX_HEIGHT * pixel_scale;
-        let actual_width = PIX_WIDTH * pixel_scale;
-        let mut copy_area = None;
-        if !full_screen {
-            actual_height -= 16 * pixel_scale;
-            copy_area =
-                Some(sdl2::rect::Rect::new(0, 8, PIX_WIDTH, PIX_HEIGHT - 16));
-        }
+        let width = overscan.width();
+        let height = overscan.height();
+        /* the NTSC output is wider, and already has the 8:7 pixel aspect */
+        let (tex_width, actual_width) = match ntsc {
+            Some(_) => {
+                let w = ntsc_width(width) as u32;
+                (w, w * pixel_scale / 2)
+            }
+            None => (width as u32, width as u32 * pixel_scale),
+        };
+        let actual_height = height as u32 * pixel_scale;
         let window = video_subsystem
             .window("RuNES", actual_width, actual_height)
             .position_centered()
@@ -308,49 +342,88 @@ impl<'a> SDLWindow<'a> {
         canvas.set_draw_color(sdl2::pixels::Color::RGB(255, 255, 255));
         canvas.clear();
         canvas.present();
+        let fb_pitch = tex_width as usize * 3;
         SDLWindow {
             canvas,
-            frame_buffer: [0; FB_SIZE],
+            frame_buffer: vec![0; height * fb_pitch],
+            fb_pitch,
             texture: texture_creator
                 .create_texture_streaming(
                     sdl2::pixels::PixelFormatEnum::RGB24,
-                    PIX_WIDTH,
-                    PIX_HEIGHT,
+                    tex_width,
+                    height as u32,
                 )
                 .unwrap(),
             event,
-            copy_area,
+            overscan,
+            palette,
+            indices: match ntsc {
+                Some(_) => vec![0; width * height],
+                None => Vec::new(),
+            },
+            ntsc_out: match ntsc {
+                Some(_) => vec![0; tex_width as usize * height],
+                None => Vec::new(),
+            },
+            ntsc: ntsc.map(Box::new),
+            ntsc_phase: 0,
         }
     }
 }
 
-#[inline(always)]
-fn get_rgb(color: u8) -> (u8, u8, u8) {
-    let c = RGB_COLORS[color as usize];
-    ((c >> 16) as u8, ((c >> 8) & 0xff) as u8, (c & 0xff) as u8)
-}
-
 impl<'a> ppu::Screen for SDLWindow<'a> {
-    #[inline(always)]
-    fn put(&mut self, x: u8, y: u8, color: u8) {
-        let (r, g, b) = get_rgb(color);
-        let base = (y as usize * FB_PITCH) + x as usize * 3;
-        self.frame_buffer[base] = r;
-        self.frame_buffer[base + 1] = g;
-        self.frame_buffer[base + 2] = b;
+    fn put(&mut self, x: u8, y: u8, color: u16) {
+        let base = y as usize * self.fb_pitch + x as usize * 3;
+        let (r, g, b) = self.palette.get_rgb(color);
+        self.frame_buffer[base..base + 3].copy_from_slice(&[r, g, b]);
+    }
+
+    fn put_line(&mut self, y: u8, line: &[u16]) {
+        let base = y as usize * self.fb_pitch;
+        let row = &mut self.frame_buffer[base..base + line.len() * 3];
+        for (p, &color) in row.chunks_exact_mut(3).zip(line.iter()) {
+            let (r, g, b) = self.palette.get_rgb(color);
+            p[0] = r;
+            p[1] = g;
+            p[2] = b;
+        }
+    }
+
+    /* the NTSC filter works on the whole frame of color indices */
+    fn frame_buffer(&mut self) -> Option<&mut [u16]> {
+        match self.ntsc {
+            Some(_) => Some(&mut self.indices),
+            None => None,
+        }
+    }
+
+    fn overscan(&self) -> Overscan {
+        self.overscan
     }
 
     fn render(&mut self) {
+        if let Some(ntsc) = &self.ntsc {
+            ntsc.filter(
+                &self.indices,
+                &self.overscan,
+                self.ntsc_phase,
+                &mut self.ntsc_out,
+            );
+            self.ntsc_phase = (self.ntsc_phase + 1) % 3;
+            for (i, c) in self.ntsc_out.iter().enumerate() {
+                self.frame_buffer[i * 3] = (c >> 16) as u8;
+                self.frame_buffer[i * 3 + 1] = (c >> 8) as u8;
+                self.frame_buffer[i * 3 + 2] = *c as u8;
+            }
+        }
         self.texture
-            .update(None, &self.frame_buffer, FB_PITCH)
+            .update(None, &self.frame_buffer, self.fb_pitch)
             .unwrap();
     }
 
     fn frame(&mut self) {
         self.canvas.clear();
-        self.canvas
-            .copy(&self.texture, self.copy_area, None)
-            .unwrap();
+        self.canvas.copy(&self.texture, None, None).unwrap();
         self.canvas.present();
         self.event.poll();
     }
@@ -499,6 +572,36 @@ fn main() {
                 .required(false)
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("overscan")
+                .help(
+                    "Set the pixels cropped from each side as \
+                     top,bottom,left,right (8,8,0,0 by default)",
+                )
+                .long("overscan")
+                .required(false)
+                .conflicts_with("full")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("palette")
+                .help(
+                    "Use a .pal file, or one of the built-in palettes: \
+                     default, ntsc (generated), rgb (2C03/2C05) or \
+                     2c04-1 to 2c04-4 (RP2C04-0001 to 0004)",
+                )
+                .short("p")
+                .long("palette")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ntsc")
+                .help("Simulate the NTSC composite video signal")
+                .long("ntsc")
+                .required(false)
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("INPUT")
                 .help("iNES ROM file")
@@ -537,6 +640,77 @@ fn main() {
                 .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("ram-init")
+                .help(
+                    "Set power-on RAM contents: zeros, ones, fceux, \
+                     random or random:<seed> (zeros by default)",
+                )
+                .long("ram-init")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cheat")
+                .help(
+                    "Add a Game Genie code or a raw code (AAAA:VV or \
+                     AAAA?CC:VV), F5 toggles cheats while running",
+                )
+                .short("c")
+                .long("cheat")
+                .required(false)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trigger")
+                .help(
+                    "Report the frame when the memory condition (in \
+                     RetroAchievements syntax) is first met",
+                )
+                .long("trigger")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trigger-timeout")
+                .help(
+                    "Exit once the trigger is met, or with a failure status \
+                     if it is not met within the given number of frames",
+                )
+                .long("trigger-timeout")
+                .required(false)
+                .requires("trigger")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("region")
+                .help(
+                    "Override the console region: ntsc, pal or dendy \
+                     (detected from the header by default)",
+                )
+                .long("region")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-sprite-limit")
+                .help(
+                    "Draw all the sprites on a scanline to remove flicker \
+                     (not accurate)",
+                )
+                .long("no-sprite-limit")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("oam-glitches")
+                .help("Emulate OAM decay and corruption")
+                .long("oam-glitches")
+                .required(false)
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("no-state")
                 .help("Power up the emulator with initial state")
@@ -551,7 +725,44 @@ fn main() {
         8,
         std::cmp::max(1, value_t!(matches, "scale", u32).unwrap_or(3)),
     );
-    let full = matches.is_present("full");
+    let overscan = match matches.value_of("overscan") {
+        Some(s) => {
+            let v: Vec<u8> =
+                s.split(',').filter_map(|x| x.trim().parse().ok()).collect();
+            match if v.len() == 4 {
+                Overscan::new(v[0], v[1], v[2], v[3])
+            } else {
+                None
+            } {
+                Some(o) => o,
+                None => {
+                    println!("invalid overscan: {}", s);
+                    exit(1)
+                }
+            }
+        }
+        None if matches.is_present("full") => Overscan::none(),
+        None => Overscan::ntsc(),
+    };
+    let palette = match matches.value_of("palette").unwrap_or("default") {
+        "default" => Palette::new(),
+        "ntsc" => Palette::from_ntsc(&NtscParams::new()),
+        "rgb" => Palette::from_rgb_ppu(),
+        "2c04-1" => Palette::from_rp2c04(1).unwrap(),
+        "2c04-2" => Palette::from_rp2c04(2).unwrap(),
+        "2c04-3" => Palette::from_rp2c04(3).unwrap(),
+        "2c04-4" => Palette::from_rp2c04(4).unwrap(),
+        fname => match std::fs::read(fname)
+            .ok()
+            .and_then(|data| Palette::from_pal(&data))
+        {
+            Some(p) => p,
+            None => {
+                println!("invalid palette file: {}", fname);
+                exit(1);
+            }
+        },
+    };
 
     let fname = matches.value_of("INPUT").unwrap();
     let load_state_name = matches.value_of("load");
@@ -560,21 +771,63 @@ fn main() {
     let load_sram_name = matches.value_of("load-sram");
     let default_state_name = fname.to_string() + ".runes";
     let default_sram_name = fname.to_string() + ".runes_sram";
+    let default_cheats_name = fname.to_string() + ".runes_cheats";
+    let trigger =
+        matches
+            .value_of("trigger")
+            .map(|s| match Trigger::parse(s) {
+                Some(t) => t,
+                None => {
+                    println!("invalid trigger: {}", s);
+                    exit(1);
+                }
+            });
+    let trigger_timeout = value_t!(matches, "trigger-timeout", u32).ok();
     let no_state = matches.is_present("no-state");
+    let no_sprite_limit = matches.is_present("no-sprite-limit");
+    let oam_glitches = matches.is_present("oam-glitches");
+    let ram_pattern = match matches.value_of("ram-init").unwrap_or("zeros") {
+        "zeros" => utils::RamPattern::Zeros,
+        "ones" => utils::RamPattern::Ones,
+        "fceux" => utils::RamPattern::Fceux,
+        "random" => utils::RamPattern::Random(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .subsec_nanos(),
+        ),
+        s => match s.strip_prefix("random:").map(|x| x.parse::<u32>()) {
+            Some(Ok(seed)) => utils::RamPattern::Random(seed),
+            _ => {
+                println!("invalid RAM init pattern: {}", s);
+                exit(1);
+            }
+        },
+    };
 
     /* load and parse iNES file */
     let mut file = File::open(fname).unwrap();
     let mut rheader = [0; 16];
     file.read(&mut rheader[..]).unwrap();
     let header = unsafe { transmute::<[u8; 16], INesHeader>(rheader) };
-    let mirror = match ((header.flags6 >> 2) & 2) | (header.flags6 & 1) {
-        0 => MirrorType::Horizontal,
-        1 => MirrorType::Vertical,
-        2 => MirrorType::Single0,
-        3 => MirrorType::Single1,
-        _ => MirrorType::Four,
+    let mirror = if header.flags6 & 0x08 == 0x08 {
+        MirrorType::Four
+    } else if header.flags6 & 0x01 == 0x01 {
+        MirrorType::Vertical
+    } else {
+        MirrorType::Horizontal
     };
     let mapper_id = (header.flags7 & 0xf0) | (header.flags6 >> 4);
+    let region = match matches.value_of("region") {
+        None => Region::from_ines_header(&rheader),
+        Some("ntsc") => Region::NTSC,
+        Some("pal") => Region::PAL,
+        Some("dendy") => Region::Dendy,
+        Some(r) => {
+            println!("invalid region: {}", r);
+            exit(1)
+        }
+    };
     if std::str::from_utf8(&header.magic).unwrap() != "NES\x1a" {
         println!("not an iNES file");
         exit(1);
@@ -647,7 +900,18 @@ fn main() {
     }
 
     let event = SDLEventPoller::new(sdl_context.event_pump().unwrap());
-    let mut win = SDLWindow::new(&video_subsystem, &event, scale, full);
+    let mut win = SDLWindow::new(
+        &video_subsystem,
+        &event,
+        scale,
+        overscan,
+        palette,
+        if matches.is_present("ntsc") {
+            Some(NtscFilter::new(&NtscParams::new()))
+        } else {
+            None
+        },
+    );
 
     /* construct mapper from cartridge data */
     let cart = SimpleCart::new(chr_rom, prg_rom, sram, mirror);
@@ -669,6 +933,7 @@ fn main() {
     let mut apu = APU::new(&mut spkr);
     let cpu_ptr = &mut cpu as *mut mos6502::CPU;
     cpu.mem.bus.attach(cpu_ptr, &mut ppu, &mut apu);
+    cpu.mem.bus.set_region(region);
 
     let load_state = !no_state &&
         match match load_state_name {
@@ -690,6 +955,9 @@ fn main() {
         };
 
     if !load_state {
+        let mut init = utils::RamInit::new(ram_pattern);
+        cpu.mem.powerup(&mut init);
+        ppu.powerup(&mut init);
         if let Some(f) = match load_sram_name {
             Some(s) => Some(File::open(s).unwrap()),
             None => match File::open(&default_sram_name) {
@@ -702,14 +970,64 @@ fn main() {
         }
         cpu.powerup()
     }
+    /* user settings, not part of the loaded state */
+    ppu.no_sprite_limit = no_sprite_limit;
+    ppu.oam_glitches = oam_glitches;
+
+    /* cheats are kept per ROM, regardless of the machine state */
+    if let Ok(f) = File::open(&default_cheats_name) {
+        if !cpu.mem.cheats.load(&mut FileIO(f)) {
+            println!("failed to load cheats");
+            cpu.mem.cheats.clear()
+        }
+    }
+    if let Some(codes) = matches.values_of("cheat") {
+        for code in codes {
+            if cpu.mem.cheats.add_code(code).is_none() {
+                println!("invalid or too many cheat codes: {}", code);
+                exit(1);
+            }
+        }
+    }
+
+    cpu.mem.set_trigger(trigger);
 
     audio_dev.resume();
+    let mut nframes = 0;
+    let mut last_frame = cpu.mem.bus.get_ppu().frame;
     loop {
         /* consume the leftover cycles from the last instruction */
         while cpu.cycle > 0 {
             cpu.mem.bus.tick()
         }
 
+        let frame = cpu.mem.bus.get_ppu().frame;
+        if frame != last_frame {
+            last_frame = frame;
+            nframes += 1;
+            if cpu.mem.get_trigger_met().is_some() {
+                println!("trigger met at frame {}", nframes);
+                if trigger_timeout.is_some() {
+                    exit(0)
+                }
+                cpu.mem.set_trigger(None)
+            } else if trigger_timeout.map_or(false, |n| nframes >= n) {
+                println!("trigger not met within {} frames", nframes);
+                exit(1)
+            }
+        }
+
+        if event.take_cheat_toggle() {
+            let active = !cpu.mem.cheats.is_active();
+            cpu.mem.cheats.set_active(active);
+            println!("cheats {}", if active { "enabled" } else { "disabled" });
+        }
+
+        if event.take_reset() {
+            cpu.mem.bus.reset();
+            println!("reset")
+        }
+
         if event.is_exiting() {
             {
                 let mut file = FileIO(
@@ -734,6 +1052,12 @@ fn main() {
                 );
                 mapper.get_cart().save_sram(&mut file);
             }
+            {
+                /* also replaces a stale or rejected list */
+                let mut file =
+                    FileIO(File::create(&default_cheats_name).unwrap());
+                cpu.mem.cheats.save(&mut file);
+            }
             exit(0);
         }
         //print_cpu_trace(&cpu);
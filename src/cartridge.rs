@@ -13,6 +13,7 @@ pub enum BankType {
     PrgRom, /* program rom */
     ChrRom, /* pattern rom */
     Sram,   /* save ram */
+    NtRam,  /* extra nametable ram (four-screen boards) */
 }
 
 pub trait Cartridge {
@@ -0,0 +1,265 @@
+use crate::utils::{Read, Write};
+
+pub const MAX_CHEATS: usize = 64;
+
+const GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+/* bytes per cheat in a saved list: address (little-endian), value, compare
+ * value and flags */
+const CHEAT_SIZE: usize = 5;
+const FLAG_COMPARE: u8 = 1;
+const FLAG_ENABLED: u8 = 2;
+
+#[derive(Copy, Clone)]
+pub struct Cheat {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+    pub enabled: bool,
+}
+
+fn genie_letter(c: u8) -> Option<u8> {
+    let c = c.to_ascii_uppercase();
+    GENIE_LETTERS.iter().position(|&l| l == c).map(|i| i as u8)
+}
+
+fn parse_hex(s: &str, max_len: usize) -> Option<u16> {
+    if s.is_empty() || s.len() > max_len {
+        return None
+    }
+    u16::from_str_radix(s, 16).ok()
+}
+
+impl Cheat {
+    pub fn new(addr: u16, value: u8, compare: Option<u8>) -> Self {
+        Cheat {
+            addr,
+            value,
+            compare,
+            enabled: true,
+        }
+    }
+
+    /* decode a 6- or 8-letter Game Genie code */
+    pub fn from_game_genie(code: &str) -> Option<Self> {
+        let code = code.as_bytes();
+        if code.len() != 6 && code.len() != 8 {
+            return None
+        }
+        let mut n = [0u8; 8];
+        for (i, c) in code.iter().enumerate() {
+            n[i] = genie_letter(*c)?
+        }
+        let addr = 0x8000 |
+            ((n[3] as u16 & 7) << 12) |
+            ((n[5] as u16 & 7) << 8) |
+            ((n[4] as u16 & 8) << 8) |
+            ((n[2] as u16 & 7) << 4) |
+            ((n[1] as u16 & 8) << 4) |
+            (n[4] as u16 & 7) |
+            (n[3] as u16 & 8);
+        let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+        Some(if code.len() == 6 {
+            Cheat::new(addr, value | (n[5] & 8), None)
+        } else {
+            let compare =
+                ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+            Cheat::new(addr, value | (n[7] & 8), Some(compare))
+        })
+    }
+
+    /* decode a raw code in the form of "AAAA:VV" or "AAAA?CC:VV" */
+    pub fn from_raw(code: &str) -> Option<Self> {
+        let mut parts = code.splitn(2, ':');
+        let lhs = parts.next()?;
+        let value = parse_hex(parts.next()?, 2)? as u8;
+        let mut lhs = lhs.splitn(2, '?');
+        let addr = parse_hex(lhs.next()?, 4)?;
+        let compare = match lhs.next() {
+            Some(c) => Some(parse_hex(c, 2)? as u8),
+            None => None,
+        };
+        Some(Cheat::new(addr, value, compare))
+    }
+
+    pub fn parse(code: &str) -> Option<Self> {
+        let code = code.trim();
+        if code.contains(':') {
+            Cheat::from_raw(code)
+        } else {
+            Cheat::from_game_genie(code)
+        }
+    }
+
+    /* codes targeting RAM (or cartridge SRAM) are frozen once per frame,
+     * codes targeting PRG-ROM substitute what the CPU reads */
+    #[inline(always)]
+    pub fn is_freeze(&self) -> bool {
+        self.addr < 0x8000
+    }
+
+    fn encode(&self) -> [u8; CHEAT_SIZE] {
+        let flags = (if self.compare.is_some() {
+            FLAG_COMPARE
+        } else {
+            0
+        }) | (if self.enabled { FLAG_ENABLED } else { 0 });
+        [
+            self.addr as u8,
+            (self.addr >> 8) as u8,
+            self.value,
+            self.compare.unwrap_or(0),
+            flags,
+        ]
+    }
+
+    fn decode(b: &[u8; CHEAT_SIZE]) -> Option<Self> {
+        let flags = b[4];
+        if flags & !(FLAG_COMPARE | FLAG_ENABLED) != 0 {
+            return None
+        }
+        Some(Cheat {
+            addr: b[0] as u16 | (b[1] as u16) << 8,
+            value: b[2],
+            compare: if flags & FLAG_COMPARE != 0 {
+                Some(b[3])
+            } else {
+                None
+            },
+            enabled: flags & FLAG_ENABLED != 0,
+        })
+    }
+}
+
+fn read_exact(reader: &mut dyn Read, buf: &mut [u8]) -> bool {
+    reader.read(buf) == Some(buf.len())
+}
+
+fn write_all(writer: &mut dyn Write, buf: &[u8]) -> bool {
+    writer.write(buf) == Some(buf.len())
+}
+
+pub struct CheatEngine {
+    cheats: [Cheat; MAX_CHEATS],
+    len: usize,
+    active: bool,
+}
+
+impl CheatEngine {
+    pub fn new() -> Self {
+        CheatEngine {
+            cheats: [Cheat::new(0, 0, None); MAX_CHEATS],
+            len: 0,
+            active: true,
+        }
+    }
+
+    /* the list is stored as the active flag and the number of cheats,
+     * followed by the cheats; nothing is changed if the data is invalid */
+    pub fn load(&mut self, reader: &mut dyn Read) -> bool {
+        let mut head = [0; 2];
+        if !read_exact(reader, &mut head) ||
+            head[0] > 1 ||
+            head[1] as usize > MAX_CHEATS
+        {
+            return false
+        }
+        let mut engine = CheatEngine::new();
+        engine.active = head[0] == 1;
+        for _ in 0..head[1] {
+            let mut b = [0; CHEAT_SIZE];
+            if !read_exact(reader, &mut b) {
+                return false
+            }
+            match Cheat::decode(&b) {
+                Some(c) => engine.add(c),
+                None => return false,
+            };
+        }
+        *self = engine;
+        true
+    }
+
+    pub fn save(&self, writer: &mut dyn Write) -> bool {
+        write_all(writer, &[self.active as u8, self.len as u8]) &&
+            self.cheats[..self.len]
+                .iter()
+                .all(|c| write_all(writer, &c.encode()))
+    }
+
+    /* returns the index of the newly added cheat */
+    pub fn add(&mut self, cheat: Cheat) -> Option<usize> {
+        if self.len == MAX_CHEATS {
+            return None
+        }
+        self.cheats[self.len] = cheat;
+        self.len += 1;
+        Some(self.len - 1)
+    }
+
+    pub fn add_code(&mut self, code: &str) -> Option<usize> {
+        self.add(Cheat::parse(code)?)
+    }
+
+    pub fn remove(&mut self, idx: usize) {
+        if idx < self.len {
+            for i in idx..self.len - 1 {
+                self.cheats[i] = self.cheats[i + 1]
+            }
+            self.len -= 1
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&Cheat> {
+        self.cheats[..self.len].get(idx)
+    }
+
+    pub fn set_enabled(&mut self, idx: usize, enabled: bool) {
+        if idx < self.len {
+            self.cheats[idx].enabled = enabled
+        }
+    }
+
+    /* turn the whole engine on/off without forgetting the codes */
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    #[inline(always)]
+    pub fn read(&self, addr: u16, data: u8) -> u8 {
+        if !self.active {
+            return data
+        }
+        for c in self.cheats[..self.len].iter() {
+            if c.enabled && c.addr == addr && !c.is_freeze() {
+                match c.compare {
+                    Some(v) if v != data => (),
+                    _ => return c.value,
+                }
+            }
+        }
+        data
+    }
+}
+
+impl Default for CheatEngine {
+    fn default() -> Self {
+        CheatEngine::new()
+    }
+}
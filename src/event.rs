@@ -0,0 +1,144 @@
+use core::cell::{Cell, Ref, RefCell};
+
+/* A per-frame timeline of the CPU accesses to the PPU/APU/mapper registers
+ * and of the interrupts, stamped with the PPU position, for debugging raster
+ * effects. A frame runs from the start of one vblank to the next, so a log
+ * holds the NMI handler followed by the rendering it has set up. */
+
+/* the suggested capacity of a log: the storage given to the recorder holds
+ * two logs */
+pub const MAX_EVENTS: usize = 4096;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum EventKind {
+    Read,  /* $2000-$2007, $4000-$4017 */
+    Write, /* $2000-$2007, $4000-$4017 and the mapper registers */
+    Nmi,   /* addr is the interrupted pc */
+    Irq,   /* addr is the interrupted pc */
+    Sprite0Hit,
+}
+
+#[derive(Copy, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    pub addr: u16,
+    pub data: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    pub cycle: u64, /* CPU cycles elapsed since power-up */
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Event {
+            kind: EventKind::Read,
+            addr: 0,
+            data: 0,
+            scanline: 0,
+            dot: 0,
+            cycle: 0,
+        }
+    }
+}
+
+pub struct EventLog<'a> {
+    events: &'a mut [Event],
+    len: usize,
+    dropped: usize, /* events that did not fit */
+    frame: u32,
+}
+
+impl<'a> EventLog<'a> {
+    fn new(events: &'a mut [Event]) -> Self {
+        EventLog {
+            events,
+            len: 0,
+            dropped: 0,
+            frame: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn events(&self) -> &[Event] {
+        &self.events[..self.len]
+    }
+
+    #[inline(always)]
+    pub fn get_dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /* the PPU frame counter at the start of the log */
+    #[inline(always)]
+    pub fn get_frame(&self) -> u32 {
+        self.frame
+    }
+}
+
+/* attached to `CPUMemory` by reference, it is written through `&self` while
+ * the machine runs; the logs live in a storage provided by the frontend
+ * (e.g. `vec![Event::default(); 2 * MAX_EVENTS]`), split in two halves, and
+ * they must not be borrowed while the machine runs */
+pub struct EventRecorder<'a> {
+    logs: RefCell<[EventLog<'a>; 2]>,
+    cur: Cell<usize>,
+    enabled: Cell<bool>,
+}
+
+impl<'a> EventRecorder<'a> {
+    pub fn new(storage: &'a mut [Event]) -> Self {
+        let (a, b) = storage.split_at_mut(storage.len() / 2);
+        EventRecorder {
+            logs: RefCell::new([EventLog::new(a), EventLog::new(b)]),
+            cur: Cell::new(0),
+            enabled: Cell::new(true),
+        }
+    }
+
+    fn get_log(&self, idx: usize) -> Ref<'_, EventLog<'a>> {
+        Ref::map(self.logs.borrow(), |logs| &logs[idx])
+    }
+
+    #[inline(always)]
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled)
+    }
+
+    #[inline(always)]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    pub fn record(&self, ev: Event) {
+        if !self.enabled.get() {
+            return
+        }
+        let log = &mut self.logs.borrow_mut()[self.cur.get()];
+        if log.len == log.events.len() {
+            log.dropped += 1
+        } else {
+            log.events[log.len] = ev;
+            log.len += 1
+        }
+    }
+
+    /* close the current log and start a new one */
+    pub fn end_frame(&self, frame: u32) {
+        let next = self.cur.get() ^ 1;
+        let log = &mut self.logs.borrow_mut()[next];
+        log.len = 0;
+        log.dropped = 0;
+        log.frame = frame;
+        self.cur.set(next)
+    }
+
+    /* the log of the last complete frame */
+    pub fn get_last(&self) -> Ref<'_, EventLog<'a>> {
+        self.get_log(self.cur.get() ^ 1)
+    }
+
+    /* the log of the frame in progress */
+    pub fn get_current(&self) -> Ref<'_, EventLog<'a>> {
+        self.get_log(self.cur.get())
+    }
+}
@@ -5,6 +5,13 @@ pub mod utils;
 pub mod mos6502;
 pub mod apu;
 pub mod cartridge;
+pub mod cheat;
 pub mod controller;
+pub mod event;
 pub mod mapper;
+pub mod ntsc;
+pub mod palette;
 pub mod ppu;
+pub mod region;
+pub mod search;
+pub mod trigger;
@@ -1,14 +1,49 @@
 use core::cell::UnsafeCell;
-use core::mem::MaybeUninit;
 
 use crate::cartridge::{BankType, Cartridge, MirrorType};
 use crate::memory::{CPUBus, VMem};
 use crate::utils::{load_prefix, save_prefix, Read, Write};
 
+#[derive(Copy, Clone, PartialEq)]
+pub enum PPUAccess {
+    Read,
+    Write,
+    Address, /* only the address is driven, by a $2006 write */
+}
+
 pub trait Mapper: VMem {
     fn get_cart(&self) -> &dyn Cartridge;
     fn get_cart_mut(&mut self) -> &mut dyn Cartridge;
     fn tick(&mut self, _bus: &CPUBus) {}
+    /* called on every access to the PPU address bus (pattern tables and
+     * nametables) and when $2006 puts a new address on it, `dot` is a
+     * free-running PPU cycle counter */
+    fn ppu_bus(&mut self, _addr: u16, _access: PPUAccess, _dot: u64) {}
+    /* the board may take over the nametable accesses ($2000-$2fff, given as
+     * [0..0x1000) so that addr >> 10 is the quadrant); None/false lets the
+     * console VRAM serve the access with the usual mirroring */
+    fn read_nametable(&self, addr: u16) -> Option<u8> {
+        let cart = self.get_cart();
+        match cart.get_mirror_type() {
+            /* four-screen boards carry 4KiB of VRAM; without it, fall back
+             * to the console VRAM */
+            MirrorType::Four if cart.get_size(BankType::NtRam) >= 0x1000 => {
+                Some(cart.get_bank(0, 0x1000, BankType::NtRam)[addr as usize])
+            }
+            _ => None,
+        }
+    }
+    fn write_nametable(&mut self, addr: u16, data: u8) -> bool {
+        let cart = self.get_cart_mut();
+        match cart.get_mirror_type() {
+            MirrorType::Four if cart.get_size(BankType::NtRam) >= 0x1000 => {
+                cart.get_bank_mut(0, 0x1000, BankType::NtRam)[addr as usize] =
+                    data;
+                true
+            }
+            _ => false,
+        }
+    }
     fn load(&mut self, reader: &mut dyn Read) -> bool;
     fn save(&self, writer: &mut dyn Write) -> bool;
 }
@@ -93,29 +128,27 @@ where
     pub fn new(cart: C) -> Self {
         let prg_nbank = cart.get_size(BankType::PrgRom) >> 14;
         let chr_nbank = cart.get_size(BankType::ChrRom) >> 13;
-        unsafe {
-            let mut m = Mapper1 {
-                cart,
-                prg_nbank,
-                chr_nbank,
-                load_reg: 0x10,
-                ctl_reg: 0x0c,
-                prg_banks: MaybeUninit::uninit().assume_init(),
-                chr_banks: MaybeUninit::uninit().assume_init(),
-                sram: core::slice::from_raw_parts_mut(core::ptr::null_mut(), 0),
-            };
-            let c = &mut m.cart;
-            m.prg_banks = [
-                c.get_bank(0, 0x4000, BankType::PrgRom),
-                c.get_bank((prg_nbank - 1) << 14, 0x4000, BankType::PrgRom),
-            ];
-            m.chr_banks = [
-                c.get_bank_mut(0, 0x1000, BankType::ChrRom),
-                c.get_bank_mut(0x1000, 0x1000, BankType::ChrRom),
-            ];
-            m.sram = c.get_bank_mut(0, 0x2000, BankType::Sram);
-            m
-        }
+        let mut m = Mapper1 {
+            cart,
+            prg_nbank,
+            chr_nbank,
+            load_reg: 0x10,
+            ctl_reg: 0x0c,
+            prg_banks: Default::default(),
+            chr_banks: Default::default(),
+            sram: &mut [],
+        };
+        let c = &mut m.cart;
+        m.prg_banks = [
+            c.get_bank(0, 0x4000, BankType::PrgRom),
+            c.get_bank((prg_nbank - 1) << 14, 0x4000, BankType::PrgRom),
+        ];
+        m.chr_banks = [
+            c.get_bank_mut(0, 0x1000, BankType::ChrRom),
+            c.get_bank_mut(0x1000, 0x1000, BankType::ChrRom),
+        ];
+        m.sram = c.get_bank_mut(0, 0x2000, BankType::Sram);
+        m
     }
 
     fn write_loadreg(&mut self, addr: u16, data: u8) {
@@ -335,26 +368,21 @@ where
 {
     pub fn new(cart: C) -> Self {
         let nbank = cart.get_size(BankType::PrgRom) >> 14;
-        unsafe {
-            let mut m = Mapper2 {
-                cart,
-                prg_nbank: nbank,
-                prg_banks: MaybeUninit::uninit().assume_init(),
-                chr_bank: core::slice::from_raw_parts_mut(
-                    core::ptr::null_mut(),
-                    0,
-                ),
-                sram: core::slice::from_raw_parts_mut(core::ptr::null_mut(), 0),
-            };
-            let c = &mut m.cart;
-            m.prg_banks = [
-                c.get_bank(0, 0x4000, BankType::PrgRom),
-                c.get_bank((nbank - 1) << 14, 0x4000, BankType::PrgRom),
-            ];
-            m.chr_bank = c.get_bank_mut(0, 0x2000, BankType::ChrRom);
-            m.sram = c.get_bank_mut(0, 0x2000, BankType::Sram);
-            m
-        }
+        let mut m = Mapper2 {
+            cart,
+            prg_nbank: nbank,
+            prg_banks: Default::default(),
+            chr_bank: &mut [],
+            sram: &mut [],
+        };
+        let c = &mut m.cart;
+        m.prg_banks = [
+            c.get_bank(0, 0x4000, BankType::PrgRom),
+            c.get_bank((nbank - 1) << 14, 0x4000, BankType::PrgRom),
+        ];
+        m.chr_bank = c.get_bank_mut(0, 0x2000, BankType::ChrRom);
+        m.sram = c.get_bank_mut(0, 0x2000, BankType::Sram);
+        m
     }
 }
 
@@ -427,8 +455,14 @@ where
     irq_reload: u8,
     irq_counter: u8,
     irq_enable: bool,
+    irq_pending: bool,
+    a12_high: bool,
+    a12_fall: u64, /* the dot when A12 went low */
 }
 
+/* A12 has to stay low for about three M2 cycles before a rise is seen */
+const MMC3_A12_FILTER: u64 = 10;
+
 impl<'a, C> VMem for Mapper4<'a, C>
 where
     C: Cartridge,
@@ -476,7 +510,11 @@ where
             },
             /* [0xe000..0xffff] */
             _ => match addr & 1 {
-                0 => self.irq_enable = false,
+                /* disabling also acknowledges the pending IRQ */
+                0 => {
+                    self.irq_enable = false;
+                    self.irq_pending = false
+                }
                 _ => self.irq_enable = true,
             },
         }
@@ -503,6 +541,9 @@ where
 
     #[inline(always)]
     fn write_mirror(&mut self, data: u8) {
+        if let MirrorType::Four = self.cart.get_mirror_type() {
+            return /* hardwired by the board */
+        }
         self.cart.set_mirror_type(match data & 1 {
             0 => MirrorType::Vertical,
             _ => MirrorType::Horizontal,
@@ -585,42 +626,43 @@ where
     pub fn new(cart: C) -> Self {
         let prg_nbank = cart.get_size(BankType::PrgRom) >> 13;
         let chr_nbank = cart.get_size(BankType::ChrRom) >> 10;
-        unsafe {
-            let mut m = Mapper4 {
-                cart,
-                prg_nbank,
-                chr_nbank,
-                prg_mode: 0,
-                chr_inv: 0,
-                reg_idx: 0,
-                regs: [0; 8],
-                prg_banks: MaybeUninit::uninit().assume_init(),
-                chr_banks: MaybeUninit::uninit().assume_init(),
-                sram: core::slice::from_raw_parts_mut(core::ptr::null_mut(), 0),
-                irq_reload: 0,
-                irq_counter: 0,
-                irq_enable: false,
-            };
-            m.prg_banks = [
-                m.get_prgbank(0),
-                m.get_prgbank(1),
-                m.get_prgbank((prg_nbank - 2) as u8),
-                m.get_prgbank((prg_nbank - 1) as u8),
-            ];
-            m.chr_banks = [
-                m.get_chrbank(0),
-                m.get_chrbank(0),
-                m.get_chrbank(0),
-                m.get_chrbank(0),
-                m.get_chrbank(0),
-                m.get_chrbank(0),
-                m.get_chrbank(0),
-                m.get_chrbank(0),
-            ];
-            let c = &mut m.cart;
-            m.sram = c.get_bank_mut(0, 0x2000, BankType::Sram);
-            m
-        }
+        let mut m = Mapper4 {
+            cart,
+            prg_nbank,
+            chr_nbank,
+            prg_mode: 0,
+            chr_inv: 0,
+            reg_idx: 0,
+            regs: [0; 8],
+            prg_banks: Default::default(),
+            chr_banks: Default::default(),
+            sram: &mut [],
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_enable: false,
+            irq_pending: false,
+            a12_high: false,
+            a12_fall: 0,
+        };
+        m.prg_banks = [
+            m.get_prgbank(0),
+            m.get_prgbank(1),
+            m.get_prgbank((prg_nbank - 2) as u8),
+            m.get_prgbank((prg_nbank - 1) as u8),
+        ];
+        m.chr_banks = [
+            m.get_chrbank(0),
+            m.get_chrbank(0),
+            m.get_chrbank(0),
+            m.get_chrbank(0),
+            m.get_chrbank(0),
+            m.get_chrbank(0),
+            m.get_chrbank(0),
+            m.get_chrbank(0),
+        ];
+        let c = &mut m.cart;
+        m.sram = c.get_bank_mut(0, 0x2000, BankType::Sram);
+        m
     }
 }
 
@@ -636,23 +678,35 @@ where
     }
 
     fn tick(&mut self, bus: &CPUBus) {
-        let ppu = bus.get_ppu();
-        if ppu.cycle != 260 {
+        /* the IRQ line stays asserted until acknowledged through $e000 */
+        if self.irq_pending {
+            bus.get_cpu().trigger_irq();
+        }
+    }
+
+    fn ppu_bus(&mut self, addr: u16, _access: PPUAccess, dot: u64) {
+        if addr & 0x1000 == 0 {
+            if self.a12_high {
+                self.a12_high = false;
+                self.a12_fall = dot
+            }
             return
         }
-        if ppu.scanline > 239 && ppu.scanline < 261 {
+        if self.a12_high {
             return
         }
-        if !ppu.get_show_bg() && !ppu.get_show_sp() {
+        self.a12_high = true;
+        if dot - self.a12_fall < MMC3_A12_FILTER {
             return
         }
+        /* a filtered rise of A12 clocks the scanline counter */
         if self.irq_counter == 0 {
             self.irq_counter = self.irq_reload
         } else {
-            self.irq_counter -= 1;
-            if self.irq_counter == 0 && self.irq_enable {
-                bus.get_cpu().trigger_irq();
-            }
+            self.irq_counter -= 1
+        }
+        if self.irq_counter == 0 && self.irq_enable {
+            self.irq_pending = true
         }
     }
 
@@ -678,6 +732,9 @@ where
             load_prefix(&mut self.irq_reload, 0, reader) &&
             load_prefix(&mut self.irq_counter, 0, reader) &&
             load_prefix(&mut self.irq_enable, 0, reader) &&
+            load_prefix(&mut self.irq_pending, 0, reader) &&
+            load_prefix(&mut self.a12_high, 0, reader) &&
+            load_prefix(&mut self.a12_fall, 0, reader) &&
             self.cart.load(reader)
     }
 
@@ -709,6 +766,9 @@ where
             save_prefix(&self.irq_reload, 0, writer) &&
             save_prefix(&self.irq_counter, 0, writer) &&
             save_prefix(&self.irq_enable, 0, writer) &&
+            save_prefix(&self.irq_pending, 0, writer) &&
+            save_prefix(&self.a12_high, 0, writer) &&
+            save_prefix(&self.a12_fall, 0, writer) &&
             self.cart.save(writer)
     }
 }
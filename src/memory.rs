@@ -1,14 +1,18 @@
 use core::cell::Cell;
-use core::mem::size_of;
+use core::mem::{offset_of, size_of};
 use core::ptr::null_mut;
 
 use crate::apu::APU;
-use crate::cartridge::MirrorType;
+use crate::cartridge::{BankType, MirrorType};
+use crate::cheat::CheatEngine;
 use crate::controller::Controller;
-use crate::mapper::RefMapper;
+use crate::event::{Event, EventKind, EventRecorder};
+use crate::mapper::{PPUAccess, RefMapper};
 use crate::mos6502::CPU;
 use crate::ppu::PPU;
-use crate::utils::{load_prefix, save_prefix, Read, Write};
+use crate::region::Region;
+use crate::trigger::Trigger;
+use crate::utils::{load_prefix, save_prefix, RamInit, Read, Write};
 
 pub trait VMem {
     fn read(&self, addr: u16) -> u8;
@@ -18,8 +22,13 @@ pub trait VMem {
 #[repr(C)]
 pub struct CPUBus<'a> {
     /*-- begin state --*/
+    cycles: Cell<u64>, /* CPU cycles elapsed since power-up */
     nmi_after_tick: Cell<bool>,
-    cpu_stall: Cell<u32>,
+    /* PPU dots per CPU cycle is ppu_num / ppu_den, ppu_frac keeps the
+     * remainder */
+    ppu_num: Cell<u8>,
+    ppu_den: Cell<u8>,
+    ppu_frac: Cell<u8>,
     /*-- end state --*/
     cpu: *mut CPU<'a>,
     ppu: *mut PPU<'a>,
@@ -28,7 +37,8 @@ pub struct CPUBus<'a> {
 
 macro_rules! CPUBUS_IGNORED_SIZE {
     () => {
-        size_of::<*mut CPU>() + size_of::<*mut PPU>() + size_of::<*mut APU>()
+        /* everything after ppu_frac, so that the padding is not saved */
+        size_of::<CPUBus>() - offset_of!(CPUBus, ppu_frac) - size_of::<u8>()
     };
 }
 
@@ -39,7 +49,10 @@ impl<'a> CPUBus<'a> {
             cpu: null_mut(),
             apu: null_mut(),
             nmi_after_tick: Cell::new(false),
-            cpu_stall: Cell::new(0),
+            cycles: Cell::new(0),
+            ppu_num: Cell::new(3),
+            ppu_den: Cell::new(1),
+            ppu_frac: Cell::new(0),
         }
     }
 
@@ -62,6 +75,27 @@ impl<'a> CPUBus<'a> {
         self.apu = apu;
     }
 
+    /* must be called after attach */
+    pub fn set_region(&self, region: Region) {
+        let (num, den) = region.ppu_ratio();
+        self.ppu_num.set(num);
+        self.ppu_den.set(den);
+        self.ppu_frac.set(0);
+        self.get_ppu().set_region(region);
+        self.get_apu().set_region(region);
+    }
+
+    /* press the reset button of the console: the CPU, PPU and APU are
+     * reset while the memory and the cartridge are left alone; call it
+     * between instructions */
+    pub fn reset(&self) {
+        self.nmi_after_tick.set(false);
+        self.ppu_frac.set(0);
+        self.get_ppu().reset();
+        self.get_apu().reset();
+        self.get_cpu().reset()
+    }
+
     #[inline(always)]
     pub fn get_cpu(&self) -> &'a mut CPU<'a> {
         unsafe { &mut *self.cpu }
@@ -75,31 +109,57 @@ impl<'a> CPUBus<'a> {
         unsafe { &mut *self.apu }
     }
 
-    pub fn cpu_stall(&self, delta: u32) {
-        self.cpu_stall.set(self.cpu_stall.get() + delta)
+    #[inline(always)]
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles.get()
+    }
+
+    /* the APU alternates between get (read) and put (write) cycles, DMA
+     * units can only read on get cycles */
+    #[inline(always)]
+    pub fn is_get_cycle(&self) -> bool {
+        self.cycles.get() & 1 == 0
     }
 
     pub fn tick(&self) {
+        self.get_cpu().tick();
+        self.tick_devices()
+    }
+
+    /* a cycle taken away from the CPU by DMA */
+    pub fn stall(&self) {
+        self.tick_devices()
+    }
+
+    fn tick_devices(&self) {
         let cpu = self.get_cpu();
         let ppu = self.get_ppu();
         let apu = self.get_apu();
 
-        let cpu_stall = self.cpu_stall.get();
-        if cpu_stall == 0 {
-            cpu.tick()
-        } else {
-            self.cpu_stall.set(cpu_stall - 1)
-        }
-        if apu.tick(self) {
+        self.cycles.set(self.cycles.get() + 1);
+        if apu.tick() {
             cpu.trigger_irq()
         }
 
-        let first = ppu.tick(self);
-        let second = ppu.tick(self);
-        let third = ppu.tick(self);
+        let frame = ppu.frame;
+        let frac = self.ppu_frac.get() + self.ppu_num.get();
+        let den = self.ppu_den.get();
+        self.ppu_frac.set(frac % den);
+        let mut first = false;
+        let mut nmi = false;
+        for i in 0..frac / den {
+            let sp0 = ppu.get_flag_sprite_zero();
+            if ppu.tick(self) {
+                first |= i == 0;
+                nmi = true
+            }
+            if !sp0 && ppu.get_flag_sprite_zero() {
+                cpu.mem.record_event(EventKind::Sprite0Hit, 0, 0)
+            }
+        }
         let mut nmi_after_tick = false;
 
-        if first || second || third {
+        if nmi {
             nmi_after_tick = !first;
             if cpu.cycle == 0 && nmi_after_tick {
                 cpu.trigger_delayed_nmi()
@@ -109,6 +169,9 @@ impl<'a> CPUBus<'a> {
             //println!("nmi");
         }
         self.nmi_after_tick.set(nmi_after_tick);
+        if ppu.frame != frame {
+            cpu.mem.end_frame()
+        }
         //println!("tick {} {}", ppu.scanline, ppu.cycle);
     }
 }
@@ -125,6 +188,10 @@ pub struct CPUMemory<'a> {
     mapper: &'a RefMapper<'a>,
     ctl1: Option<&'a dyn Controller>,
     ctl2: Option<&'a dyn Controller>,
+    events: Option<&'a EventRecorder<'a>>,
+    pub cheats: CheatEngine,
+    trigger: Option<Trigger>,
+    trigger_met: Option<u32>, /* the frame in which the trigger was met */
 }
 
 macro_rules! CPUMEM_IGNORED_SIZE {
@@ -132,7 +199,11 @@ macro_rules! CPUMEM_IGNORED_SIZE {
         size_of::<CPUBus>() +
             size_of::<&RefMapper>() +
             size_of::<Option<&dyn Controller>>() +
-            size_of::<Option<&dyn Controller>>()
+            size_of::<Option<&dyn Controller>>() +
+            size_of::<Option<&EventRecorder>>() +
+            size_of::<CheatEngine>() +
+            size_of::<Option<Trigger>>() +
+            size_of::<Option<u32>>()
     };
 }
 
@@ -148,6 +219,10 @@ impl<'a> CPUMemory<'a> {
             mapper,
             ctl1,
             ctl2,
+            events: None,
+            cheats: CheatEngine::new(),
+            trigger: None,
+            trigger_met: None,
         }
     }
 
@@ -161,12 +236,87 @@ impl<'a> CPUMemory<'a> {
             self.bus.save(writer)
     }
 
+    /* fill the internal RAM and the cartridge PRG-RAM with their power-on
+     * contents (battery-backed data should be loaded afterwards) */
+    pub fn powerup(&mut self, init: &mut RamInit) {
+        init.fill(&mut self.sram);
+        let cart = self.mapper.get_mut().get_cart_mut();
+        let size = cart.get_size(BankType::Sram);
+        init.fill(cart.get_bank_mut(0, size, BankType::Sram));
+    }
+
+    pub fn set_event_recorder(
+        &mut self,
+        events: Option<&'a EventRecorder<'a>>,
+    ) {
+        self.events = events
+    }
+
+    #[inline(always)]
+    pub fn get_event_recorder(&self) -> Option<&'a EventRecorder<'a>> {
+        self.events
+    }
+
+    /* evaluate the trigger at the end of every frame until it is met */
+    pub fn set_trigger(&mut self, trigger: Option<Trigger>) {
+        self.trigger = trigger;
+        self.trigger_met = None
+    }
+
+    #[inline(always)]
+    pub fn get_trigger_met(&self) -> Option<u32> {
+        self.trigger_met
+    }
+
+    /* stamp the event with the current PPU position and CPU cycle */
+    pub fn record_event(&self, kind: EventKind, addr: u16, data: u8) {
+        if let Some(events) = self.events {
+            let ppu = self.bus.get_ppu();
+            events.record(Event {
+                kind,
+                addr,
+                data,
+                scanline: ppu.scanline,
+                dot: ppu.cycle,
+                cycle: self.bus.get_cycles(),
+            })
+        }
+    }
+
     pub fn get_bus(&'a self) -> &'a CPUBus<'a> {
         &self.bus
     }
 
+    /* read without any side-effect on the machine (no tick, no register
+     * access, no cheats), for debugging and searching tools */
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            /* [0x0000..0x2000) */
+            0 | 1 => self.sram[(addr & 0x07ff) as usize],
+            /* [0x2000..0x6000) */
+            2..=5 => 0,
+            /* [0x6000..0xffff) */
+            _ => self.mapper.read(addr),
+        }
+    }
+
+    /* the whole (unbanked) PRG-RAM of the cartridge */
+    pub fn get_cart_sram(&self) -> &[u8] {
+        let cart = self.mapper.get_cart();
+        cart.get_bank(0, cart.get_size(BankType::Sram), BankType::Sram)
+    }
+
     #[inline(always)]
     pub fn read_without_tick(&self, addr: u16) -> u8 {
+        let data = self._read_without_tick(addr);
+        if self.events.is_some() && (0x2000..0x4018).contains(&addr) {
+            self.record_event(EventKind::Read, addr, data)
+        }
+        data
+    }
+
+    #[inline(always)]
+    fn _read_without_tick(&self, addr: u16) -> u8 {
         let cpu = self.bus.get_cpu();
         let ppu = self.bus.get_ppu();
         match addr >> 12 {
@@ -211,7 +361,7 @@ impl<'a> CPUMemory<'a> {
             /* [0x5000..0x6000) */
             5 => 0,
             /* [0x6000..0xffff) */
-            _ => self.mapper.read(addr),
+            _ => self.cheats.read(addr, self.mapper.read(addr)),
         }
     }
 
@@ -219,6 +369,13 @@ impl<'a> CPUMemory<'a> {
     pub fn write_without_tick(&mut self, addr: u16, data: u8) {
         let cpu = self.bus.get_cpu();
         let ppu = self.bus.get_ppu();
+        if self.events.is_some() &&
+            ((0x2000..0x4018).contains(&addr) ||
+                (0x4020..0x6000).contains(&addr) ||
+                addr >= 0x8000)
+        {
+            self.record_event(EventKind::Write, addr, data)
+        }
         match addr >> 12 {
             /* [0x0000..0x2000) */
             0 | 1 => self.sram[(addr & 0x07ff) as usize] = data,
@@ -266,7 +423,7 @@ impl<'a> CPUMemory<'a> {
                     0x4013 => apu.dmc.write_reg4(data),
                     0x4015 => apu.write_status(data),
                     0x4017 => apu.write_frame_counter(data),
-                    0x4014 => ppu.write_oamdma(data, &self.bus),
+                    0x4014 => self.oam_dma(data),
                     0x4016 => {
                         if let Some(c) = self.ctl1 {
                             c.write(data)
@@ -284,10 +441,101 @@ impl<'a> CPUMemory<'a> {
             _ => self.mapper.get_mut().write(addr, data),
         }
     }
+
+    /* evaluate the trigger and re-apply the RAM-freeze cheats, called once
+     * per frame */
+    pub fn end_frame(&mut self) {
+        let frame = self.bus.get_ppu().frame;
+        if let Some(events) = self.events {
+            events.end_frame(frame)
+        }
+        if self.trigger_met.is_none() {
+            if let Some(mut t) = self.trigger.take() {
+                if t.eval(self) {
+                    self.trigger_met = Some(frame)
+                }
+                self.trigger = Some(t)
+            }
+        }
+        if !self.cheats.is_active() {
+            return
+        }
+        for i in 0..self.cheats.len() {
+            let c = *self.cheats.get(i).unwrap();
+            let addr = c.addr;
+            if !c.enabled || !c.is_freeze() || (0x2000..0x6000).contains(&addr)
+            {
+                continue /* never touch the I/O registers */
+            }
+            match c.compare {
+                Some(v) if v != self.read_without_tick(addr) => (),
+                _ => self.write_without_tick(addr, c.value),
+            }
+        }
+    }
+
+    fn dmc_fetch(&self) {
+        let apu = self.bus.get_apu();
+        let data = self.read_without_tick(apu.dmc.get_dma_addr());
+        apu.dmc.dma_fill(data)
+    }
+
+    /* DMC DMA halts the CPU on a read cycle: the halted read (and the dummy
+     * cycle after it) hit the bus again, so registers with read side-effects
+     * ($2007, $4016, ...) see extra reads */
+    fn dmc_dma(&self, addr: u16) {
+        /* back-to-back joypad reads only clock the shift register once */
+        let repeat = addr != 0x4016 && addr != 0x4017;
+        /* halt cycle */
+        self.bus.stall();
+        self.read_without_tick(addr);
+        /* dummy cycle */
+        self.bus.stall();
+        if repeat {
+            self.read_without_tick(addr);
+        }
+        /* alignment cycle */
+        if !self.bus.is_get_cycle() {
+            self.bus.stall();
+            if repeat {
+                self.read_without_tick(addr);
+            }
+        }
+        self.bus.stall();
+        self.dmc_fetch()
+    }
+
+    fn oam_dma(&self, page: u8) {
+        let apu = self.bus.get_apu();
+        let ppu = self.bus.get_ppu();
+        let mut addr = (page as u16) << 8;
+        /* halt cycle */
+        self.bus.stall();
+        /* alignment cycle */
+        if !self.bus.is_get_cycle() {
+            self.bus.stall()
+        }
+        for _ in 0..0x100 {
+            if apu.dmc.dma_pending() {
+                /* DMC steals the get cycle, then one more to realign */
+                self.bus.stall();
+                self.dmc_fetch();
+                self.bus.stall();
+            }
+            self.bus.stall();
+            let data = self.read_without_tick(addr);
+            self.bus.stall();
+            ppu.write_oamdata(data);
+            addr = addr.wrapping_add(1);
+        }
+    }
 }
 
 impl<'a> VMem for CPUMemory<'a> {
     fn read(&self, addr: u16) -> u8 {
+        if self.bus.get_apu().dmc.dma_pending() {
+            self.dmc_dma(addr)
+        }
         self.bus.tick();
         self.read_without_tick(addr)
     }
@@ -303,6 +551,7 @@ pub struct PPUMemory<'a> {
     /*-- begin state -- */
     nametable: [u8; 0x800],
     palette: [u8; 0x20],
+    dot: Cell<u64>, /* PPU cycles elapsed since power-up */
     /*-- end state --*/
     mapper: &'a RefMapper<'a>,
 }
@@ -318,6 +567,7 @@ impl<'a> PPUMemory<'a> {
         PPUMemory {
             nametable: [0; 0x800],
             palette: [0; 0x20],
+            dot: Cell::new(0),
             mapper,
         }
     }
@@ -329,6 +579,17 @@ impl<'a> PPUMemory<'a> {
     pub fn save(&self, writer: &mut dyn Write) -> bool {
         save_prefix(self, PPUMEM_IGNORED_SIZE!(), writer)
     }
+
+    pub fn powerup(&mut self, init: &mut RamInit) {
+        init.fill(&mut self.nametable);
+        init.fill(&mut self.palette);
+        for p in self.palette.iter_mut() {
+            *p &= 0x3f /* palette entries are only 6-bit wide */
+        }
+        let cart = self.mapper.get_mut().get_cart_mut();
+        let size = cart.get_size(BankType::NtRam);
+        init.fill(cart.get_bank_mut(0, size, BankType::NtRam));
+    }
 }
 
 const MIRROR_IDX: [[u8; 4]; 5] = [
@@ -356,8 +617,38 @@ fn get_mirror_palette(addr: u16) -> u16 {
 }
 
 impl<'a> PPUMemory<'a> {
+    #[inline(always)]
+    fn observe(&self, addr: u16, access: PPUAccess) {
+        self.mapper.get_mut().ppu_bus(addr, access, self.dot.get())
+    }
+
+    /* the address is driven on the bus without accessing memory */
+    #[inline(always)]
+    pub fn put_addr(&self, addr: u16) {
+        self.observe(addr & 0x3fff, PPUAccess::Address)
+    }
+
+    /* PPU cycles elapsed since power-up */
+    #[inline(always)]
+    pub fn get_dot(&self) -> u64 {
+        self.dot.get()
+    }
+
     #[inline(always)]
     pub fn read_nametable(&self, addr: u16) -> u8 {
+        self.observe(0x2000 | addr, PPUAccess::Read);
+        if let Some(data) = self.mapper.read_nametable(addr) {
+            return data
+        }
+        let mt = self.mapper.get_cart().get_mirror_type();
+        self.nametable[(get_mirror_addr(mt, addr) & 0x7ff) as usize]
+    }
+
+    /* read without the mapper seeing the access on the bus */
+    pub fn peek_nametable(&self, addr: u16) -> u8 {
+        if let Some(data) = self.mapper.read_nametable(addr) {
+            return data
+        }
         let mt = self.mapper.get_cart().get_mirror_type();
         self.nametable[(get_mirror_addr(mt, addr) & 0x7ff) as usize]
     }
@@ -369,6 +660,10 @@ impl<'a> PPUMemory<'a> {
 
     #[inline(always)]
     pub fn write_nametable(&mut self, addr: u16, data: u8) {
+        self.observe(0x2000 | addr, PPUAccess::Write);
+        if self.mapper.get_mut().write_nametable(addr, data) {
+            return
+        }
         let mt = self.mapper.get_cart().get_mirror_type();
         self.nametable[(get_mirror_addr(mt, addr) & 0x7ff) as usize] = data
     }
@@ -380,16 +675,25 @@ impl<'a> PPUMemory<'a> {
 
     #[inline(always)]
     pub fn read_mapper(&self, addr: u16) -> u8 {
+        self.observe(addr, PPUAccess::Read);
+        self.mapper.read(addr)
+    }
+
+    /* read without the mapper seeing the access on the bus */
+    #[inline(always)]
+    pub fn peek_mapper(&self, addr: u16) -> u8 {
         self.mapper.read(addr)
     }
 
     #[inline(always)]
     fn write_mapper(&self, addr: u16, data: u8) {
+        self.observe(addr, PPUAccess::Write);
         self.mapper.get_mut().write(addr, data)
     }
 
     #[inline(always)]
     pub fn tick(&self, bus: &CPUBus) {
+        self.dot.set(self.dot.get() + 1);
         self.mapper.get_mut().tick(bus)
     }
 }
@@ -2,6 +2,7 @@
 
 use core::mem::size_of;
 
+use crate::event::EventKind;
 use crate::memory::{CPUMemory, VMem};
 use crate::utils::{load_prefix, save_prefix, Read, Write};
 
@@ -650,6 +651,21 @@ enum IntType {
     DelayedNMI,
 }
 
+/* a copy of the CPU registers for debuggers and tests */
+#[derive(Copy, Clone)]
+pub struct CpuState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub status: u8,
+    pub pc: u16,
+    pub sp: u8,
+    pub cycle: u32, /* remaining cycles of the current instruction */
+    pub nmi_pending: bool,
+    pub irq_pending: bool,
+    pub cycles: u64, /* CPU cycles elapsed since power-up */
+}
+
 #[repr(C)]
 pub struct CPU<'a> {
     /*-- begin state --*/
@@ -748,6 +764,24 @@ impl<'a> CPU<'a> {
         (self.status >> 7) & 1
     }
 
+    pub fn get_state(&self) -> CpuState {
+        CpuState {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            status: self.status,
+            pc: self.pc,
+            sp: self.sp,
+            cycle: self.cycle,
+            nmi_pending: matches!(
+                self.int,
+                Some(IntType::NMI) | Some(IntType::DelayedNMI)
+            ),
+            irq_pending: matches!(self.int, Some(IntType::IRQ)),
+            cycles: self.mem.bus.get_cycles(),
+        }
+    }
+
     pub fn new(mem: CPUMemory<'a>) -> Self {
         let pc = 0;
         /* nes power up state */
@@ -795,11 +829,13 @@ impl<'a> CPU<'a> {
         if self.int.is_some() {
             match self.int {
                 Some(IntType::NMI) => {
+                    self.mem.record_event(EventKind::Nmi, self.pc, 0);
                     self.nmi();
                     self.int = None;
                     return
                 }
                 Some(IntType::IRQ) => {
+                    self.mem.record_event(EventKind::Irq, self.pc, 0);
                     self.irq();
                     self.int = None;
                     return
@@ -830,7 +866,23 @@ impl<'a> CPU<'a> {
         self.cycle -= 1
     }
 
-    #[allow(dead_code)]
+    /* run until the PPU finishes the current frame (stops at an instruction
+     * boundary right after the vblank starts) */
+    pub fn run_frame(&mut self) {
+        let ppu = self.mem.bus.get_ppu();
+        let frame = ppu.frame;
+        loop {
+            if self.cycle > 0 {
+                /* consume the leftover cycles from the last instruction */
+                self.mem.bus.tick()
+            } else if ppu.frame != frame {
+                break
+            } else {
+                self.step()
+            }
+        }
+    }
+
     pub fn reset(&mut self) {
         self.cycle = 2;
         self.pc = read16!(self.mem, RESET_VECTOR as u16);
@@ -856,11 +908,9 @@ impl<'a> CPU<'a> {
 
     #[inline(always)]
     pub fn trigger_irq(&mut self) {
-        if self.get_int() == 0 {
-            self.int = Some(match self.int {
-                Some(IntType::NMI) => IntType::NMI,
-                _ => IntType::IRQ,
-            });
+        /* a pending NMI, delayed or not, takes priority */
+        if self.get_int() == 0 && self.int.is_none() {
+            self.int = Some(IntType::IRQ)
         }
     }
 }
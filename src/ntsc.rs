@@ -0,0 +1,125 @@
+use crate::palette::{
+    math, ntsc_angle, ntsc_signal, to_u8, yiq_to_rgb, NtscParams,
+};
+use crate::ppu::Overscan;
+
+/* A composite video simulation run over a whole frame of 9-bit color
+ * indices (as given to `Screen::put`).
+ *
+ * The PPU outputs 8 samples per pixel at 12 samples per color subcarrier
+ * period, so a pixel spans 2/3 of a color cycle and colors bleed into each
+ * other. Each scanline starts 4 samples (1/3 cycle) later than the previous
+ * one and the starting phase of the frame moves as well, which gives the
+ * dot crawl. The full 256x240 picture gives NTSC_WIDTH x 240: shown with
+ * doubled lines (NTSC_WIDTH x 480), the pixels have the 8:7 aspect ratio
+ * of the real picture. */
+
+pub const NTSC_IN_WIDTH: usize = 256;
+pub const NTSC_HEIGHT: usize = 240;
+pub const NTSC_WIDTH: usize = 585;
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = NTSC_IN_WIDTH * SAMPLES_PER_PIXEL;
+const PERIOD: usize = 12;
+
+/* the output width for `width` input pixels */
+pub fn ntsc_width(width: usize) -> usize {
+    (width * NTSC_WIDTH + NTSC_IN_WIDTH / 2) / NTSC_IN_WIDTH
+}
+
+pub struct NtscFilter {
+    signal: [[f32; PERIOD]; 512],
+    cos: [f32; PERIOD],
+    sin: [f32; PERIOD],
+    gamma: [u8; 256],
+    params: NtscParams,
+}
+
+impl NtscFilter {
+    pub fn new(params: &NtscParams) -> Self {
+        let mut f = NtscFilter {
+            signal: [[0.0; PERIOD]; 512],
+            cos: [0.0; PERIOD],
+            sin: [0.0; PERIOD],
+            gamma: [0; 256],
+            params: *params,
+        };
+        for (idx, s) in f.signal.iter_mut().enumerate() {
+            for (phase, v) in s.iter_mut().enumerate() {
+                *v = ntsc_signal(idx, phase)
+            }
+        }
+        for phase in 0..PERIOD {
+            let angle = ntsc_angle(phase, params);
+            f.cos[phase] = math::cos(angle);
+            f.sin[phase] = math::sin(angle);
+        }
+        for (i, g) in f.gamma.iter_mut().enumerate() {
+            *g = to_u8(math::pow(i as f32 / 255.0, 2.2 / params.gamma))
+        }
+        f
+    }
+
+    /* `frame` holds the color indices of the area left by `overscan`,
+     * `out` receives ntsc_width(overscan.width()) x overscan.height()
+     * pixels as 0xRRGGBB; `phase` is the starting phase of the frame (e.g.
+     * the frame counter modulo 3) */
+    pub fn filter(
+        &self,
+        frame: &[u16],
+        overscan: &Overscan,
+        phase: u8,
+        out: &mut [u32],
+    ) {
+        let mut buf = [0.0f32; LINE_SAMPLES];
+        let in_width = overscan.width();
+        let out_width = ntsc_width(in_width);
+        let line_samples = in_width * SAMPLES_PER_PIXEL;
+        let line = &mut buf[..line_samples];
+        for y in 0..overscan.height() {
+            /* the phase stays that of the uncropped picture */
+            let line_phase = (phase as usize * 4 +
+                (y + overscan.top() as usize) * 4 +
+                overscan.left() as usize * SAMPLES_PER_PIXEL) %
+                PERIOD;
+            /* modulate */
+            let src = &frame[y * in_width..(y + 1) * in_width];
+            for (x, &idx) in src.iter().enumerate() {
+                let levels = &self.signal[(idx & 0x1ff) as usize];
+                let base = x * SAMPLES_PER_PIXEL;
+                let samples = &mut line[base..base + SAMPLES_PER_PIXEL];
+                for (k, v) in samples.iter_mut().enumerate() {
+                    *v = levels[(base + k + line_phase) % PERIOD]
+                }
+            }
+            /* demodulate over one subcarrier period around each output
+             * pixel */
+            let dst = &mut out[y * out_width..(y + 1) * out_width];
+            for (x, p) in dst.iter_mut().enumerate() {
+                let center = (x * 2 + 1) * line_samples / (out_width * 2);
+                let start = if center < PERIOD / 2 {
+                    0
+                } else if center + PERIOD / 2 > line_samples {
+                    line_samples - PERIOD
+                } else {
+                    center - PERIOD / 2
+                };
+                let (mut yy, mut i, mut q) = (0.0, 0.0, 0.0);
+                for (k, &v) in line[start..start + PERIOD].iter().enumerate() {
+                    let ph = (start + k + line_phase) % PERIOD;
+                    yy += v;
+                    i += v * self.cos[ph];
+                    q += v * self.sin[ph];
+                }
+                let (r, g, b) = yiq_to_rgb(
+                    yy / PERIOD as f32,
+                    i / PERIOD as f32,
+                    q / PERIOD as f32,
+                    &self.params,
+                );
+                *p = ((self.gamma[to_u8(r) as usize] as u32) << 16) |
+                    ((self.gamma[to_u8(g) as usize] as u32) << 8) |
+                    self.gamma[to_u8(b) as usize] as u32
+            }
+        }
+    }
+}
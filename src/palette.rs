@@ -0,0 +1,363 @@
+/* conversion from the 9-bit color indices given to `Screen::put` (6-bit
+ * palette color plus the three emphasis bits) to 0xRRGGBB */
+
+pub const PALETTE_SIZE: usize = 512;
+
+pub const DEFAULT_COLORS: [u32; 64] = [
+    0x666666, 0x002a88, 0x1412a7, 0x3b00a4, 0x5c007e, 0x6e0040, 0x6c0600,
+    0x561d00, 0x333500, 0x0b4800, 0x005200, 0x004f08, 0x00404d, 0x000000,
+    0x000000, 0x000000, 0xadadad, 0x155fd9, 0x4240ff, 0x7527fe, 0xa01acc,
+    0xb71e7b, 0xb53120, 0x994e00, 0x6b6d00, 0x388700, 0x0c9300, 0x008f32,
+    0x007c8d, 0x000000, 0x000000, 0x000000, 0xfffeff, 0x64b0ff, 0x9290ff,
+    0xc676ff, 0xf36aff, 0xfe6ecc, 0xfe8170, 0xea9e22, 0xbcbe00, 0x88d800,
+    0x5ce430, 0x45e082, 0x48cdde, 0x4f4f4f, 0x000000, 0x000000, 0xfffeff,
+    0xc0dfff, 0xd3d2ff, 0xe8c8ff, 0xfbc2ff, 0xfec4ea, 0xfeccc5, 0xf7d8a5,
+    0xe4e594, 0xcfef96, 0xbdf4ab, 0xb3f3cc, 0xb5ebf2, 0xb8b8b8, 0x000000,
+    0x000000,
+];
+
+/* the master palette of the RGB PPUs (2C03/2C04/2C05), 3 bits per channel */
+const RGB_PPU_COLORS: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420, 0o320, 0o120,
+    0o031, 0o040, 0o022, 0o000, 0o000, 0o000, 0o555, 0o036, 0o027, 0o407,
+    0o507, 0o704, 0o700, 0o630, 0o430, 0o140, 0o040, 0o053, 0o044, 0o000,
+    0o000, 0o000, 0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000, 0o777, 0o567,
+    0o657, 0o757, 0o747, 0o755, 0o764, 0o772, 0o773, 0o572, 0o473, 0o276,
+    0o467, 0o000, 0o000, 0o000,
+];
+
+/* the color index to master palette maps of the RP2C04-0001..0004 */
+pub const RP2C04_MAPS: [[u8; 64]; 4] = [
+    [
+        0x35, 0x23, 0x16, 0x22, 0x1c, 0x09, 0x1d, 0x15, 0x20, 0x00, 0x27, 0x05,
+        0x04, 0x28, 0x08, 0x20, 0x21, 0x3e, 0x1f, 0x29, 0x3c, 0x32, 0x36, 0x12,
+        0x3f, 0x2b, 0x2e, 0x1e, 0x3d, 0x2d, 0x24, 0x01, 0x0e, 0x31, 0x33, 0x2a,
+        0x2c, 0x0c, 0x1b, 0x14, 0x2e, 0x07, 0x34, 0x06, 0x13, 0x02, 0x26, 0x2e,
+        0x2e, 0x19, 0x10, 0x0a, 0x39, 0x03, 0x37, 0x17, 0x0f, 0x11, 0x0b, 0x0d,
+        0x38, 0x25, 0x18, 0x3a,
+    ],
+    [
+        0x2e, 0x27, 0x18, 0x39, 0x3a, 0x25, 0x1c, 0x31, 0x16, 0x13, 0x38, 0x34,
+        0x20, 0x23, 0x3c, 0x0b, 0x0f, 0x21, 0x06, 0x3d, 0x1b, 0x29, 0x1e, 0x22,
+        0x1d, 0x24, 0x0e, 0x2b, 0x32, 0x08, 0x2e, 0x03, 0x04, 0x36, 0x26, 0x33,
+        0x11, 0x1f, 0x10, 0x02, 0x14, 0x3f, 0x00, 0x09, 0x12, 0x2e, 0x28, 0x20,
+        0x3e, 0x0d, 0x2a, 0x17, 0x0c, 0x01, 0x15, 0x19, 0x2e, 0x2c, 0x07, 0x37,
+        0x35, 0x05, 0x0a, 0x2d,
+    ],
+    [
+        0x14, 0x25, 0x3a, 0x10, 0x0b, 0x20, 0x31, 0x09, 0x01, 0x2e, 0x36, 0x08,
+        0x15, 0x3d, 0x3e, 0x3c, 0x22, 0x1c, 0x05, 0x12, 0x19, 0x18, 0x17, 0x1b,
+        0x00, 0x03, 0x2e, 0x02, 0x16, 0x06, 0x34, 0x35, 0x23, 0x0f, 0x0e, 0x37,
+        0x0d, 0x27, 0x26, 0x20, 0x29, 0x04, 0x21, 0x24, 0x11, 0x2d, 0x2e, 0x1f,
+        0x2c, 0x1e, 0x39, 0x33, 0x07, 0x2a, 0x28, 0x1d, 0x0a, 0x2e, 0x32, 0x38,
+        0x13, 0x2b, 0x3f, 0x0c,
+    ],
+    [
+        0x18, 0x03, 0x1c, 0x28, 0x2e, 0x35, 0x01, 0x17, 0x10, 0x1f, 0x2a, 0x0e,
+        0x36, 0x37, 0x0b, 0x39, 0x25, 0x1e, 0x12, 0x34, 0x2e, 0x1d, 0x06, 0x26,
+        0x3e, 0x1b, 0x22, 0x19, 0x04, 0x2e, 0x3a, 0x21, 0x05, 0x0a, 0x07, 0x02,
+        0x13, 0x14, 0x00, 0x15, 0x0c, 0x3d, 0x11, 0x0f, 0x0d, 0x38, 0x2d, 0x24,
+        0x33, 0x20, 0x08, 0x16, 0x3f, 0x2b, 0x20, 0x3c, 0x2e, 0x27, 0x23, 0x31,
+        0x29, 0x32, 0x2c, 0x09,
+    ],
+];
+
+/* signal levels of the 2C02 composite output, in volts */
+const NTSC_LEVELS: [f32; 8] =
+    [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+const NTSC_BLACK: f32 = 0.518;
+const NTSC_WHITE: f32 = 1.962;
+const NTSC_ATTENUATION: f32 = 0.746;
+/* color burst phase, in 1/12 of the subcarrier period */
+const NTSC_PHASE: f32 = 3.9;
+
+/* `core` has no float math, these are good enough for palette generation */
+pub(crate) mod math {
+    pub const PI: f32 = core::f32::consts::PI;
+    const LN2: f32 = core::f32::consts::LN_2;
+
+    fn round(x: f32) -> f32 {
+        (if x < 0.0 { x - 0.5 } else { x + 0.5 }) as i32 as f32
+    }
+
+    pub fn sin(x: f32) -> f32 {
+        let x = x - round(x / (2.0 * PI)) * 2.0 * PI; /* [-pi, pi] */
+        let x2 = x * x;
+        let mut term = x;
+        let mut sum = x;
+        for i in 1..8 {
+            term *= -x2 / ((2 * i) * (2 * i + 1)) as f32;
+            sum += term
+        }
+        sum
+    }
+
+    pub fn cos(x: f32) -> f32 {
+        sin(x + PI / 2.0)
+    }
+
+    fn ln(x: f32) -> f32 {
+        /* x = m * 2^e with m in [1, 2) */
+        let bits = x.to_bits();
+        let e = ((bits >> 23) & 0xff) as i32 - 127;
+        let m = f32::from_bits((bits & 0x007fffff) | 0x3f800000);
+        /* ln(m) = 2 * atanh((m - 1) / (m + 1)) */
+        let t = (m - 1.0) / (m + 1.0);
+        let t2 = t * t;
+        let mut term = t;
+        let mut sum = 0.0;
+        for i in 0..8 {
+            sum += term / (2 * i + 1) as f32;
+            term *= t2
+        }
+        2.0 * sum + e as f32 * LN2
+    }
+
+    fn exp(x: f32) -> f32 {
+        /* x = k * ln2 + r */
+        let k = round(x / LN2);
+        let r = x - k * LN2;
+        let mut term = 1.0;
+        let mut sum = 1.0;
+        for i in 1..10 {
+            term *= r / i as f32;
+            sum += term
+        }
+        let k = k as i32;
+        if k < -126 {
+            0.0
+        } else {
+            sum * f32::from_bits(((k + 127) as u32) << 23)
+        }
+    }
+
+    pub fn pow(b: f32, e: f32) -> f32 {
+        if b <= 0.0 {
+            0.0
+        } else {
+            exp(e * ln(b))
+        }
+    }
+}
+
+/* parameters for decoding the composite signal, the defaults give a
+ * neutral picture */
+#[derive(Copy, Clone)]
+pub struct NtscParams {
+    pub hue: f32,        /* in degrees */
+    pub saturation: f32, /* 1.0 is unchanged */
+    pub contrast: f32,   /* 1.0 is unchanged */
+    pub brightness: f32, /* 0.0 is unchanged */
+    pub gamma: f32,      /* display gamma, 2.2 is unchanged */
+}
+
+impl NtscParams {
+    pub fn new() -> Self {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+impl Default for NtscParams {
+    fn default() -> Self {
+        NtscParams::new()
+    }
+}
+
+/* normalized level of the composite signal of a 9-bit color index at one
+ * of the 12 phases of the color subcarrier */
+pub(crate) fn ntsc_signal(idx: usize, phase: usize) -> f32 {
+    let color = idx & 0x0f;
+    let emphasis = idx >> 6;
+    let level = if color > 13 { 1 } else { (idx >> 4) & 3 };
+    let in_phase = |c: usize| (c + phase) % 12 < 6;
+    let mut signal = if in_phase(color) {
+        NTSC_LEVELS[level + if color < 13 { 4 } else { 0 }]
+    } else {
+        NTSC_LEVELS[level + if color == 0 { 4 } else { 0 }]
+    };
+    if color < 14 &&
+        ((emphasis & 1 != 0 && in_phase(0)) ||
+            (emphasis & 2 != 0 && in_phase(4)) ||
+            (emphasis & 4 != 0 && in_phase(8)))
+    {
+        signal *= NTSC_ATTENUATION
+    }
+    (signal - NTSC_BLACK) / (NTSC_WHITE - NTSC_BLACK)
+}
+
+/* the angle used to demodulate the chroma at the given phase */
+pub(crate) fn ntsc_angle(phase: usize, params: &NtscParams) -> f32 {
+    math::PI * (phase as f32 + NTSC_PHASE) / 6.0 + params.hue * math::PI / 180.0
+}
+
+pub(crate) fn yiq_to_rgb(
+    y: f32,
+    i: f32,
+    q: f32,
+    params: &NtscParams,
+) -> (f32, f32, f32) {
+    let y = y * params.contrast + params.brightness;
+    let i = i * params.saturation * params.contrast;
+    let q = q * params.saturation * params.contrast;
+    (
+        y + 0.946882 * i + 0.623557 * q,
+        y - 0.274788 * i - 0.635691 * q,
+        y - 1.108545 * i + 1.709007 * q,
+    )
+}
+
+pub struct Palette {
+    colors: [u32; PALETTE_SIZE],
+}
+
+#[inline(always)]
+fn pack_rgb(r: u8, g: u8, b: u8) -> u32 {
+    ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+}
+
+#[inline(always)]
+pub(crate) fn to_u8(v: f32) -> u8 {
+    if v <= 0.0 {
+        0
+    } else if v >= 1.0 {
+        255
+    } else {
+        (v * 255.0 + 0.5) as u8
+    }
+}
+
+impl Palette {
+    pub fn new() -> Self {
+        Palette::from_colors(&DEFAULT_COLORS)
+    }
+
+    /* take the 64 base colors and approximate emphasis by darkening the
+     * channels that are not emphasized */
+    pub fn from_colors(colors: &[u32; 64]) -> Self {
+        let mut p = Palette {
+            colors: [0; PALETTE_SIZE],
+        };
+        for (i, c) in p.colors.iter_mut().enumerate() {
+            let base = colors[i & 0x3f];
+            let emphasis = i >> 6;
+            let dim = |shift: u32, bit: usize| {
+                let v = (base >> shift) & 0xff;
+                if emphasis == 0 || emphasis & bit != 0 {
+                    v
+                } else {
+                    v * 3 / 4
+                }
+            };
+            *c = (dim(16, 1) << 16) | (dim(8, 2) << 8) | dim(0, 4)
+        }
+        p
+    }
+
+    /* load a .pal file: 64 colors (192 bytes) or 512 colors with all the
+     * emphasis combinations (1536 bytes) */
+    pub fn from_pal(data: &[u8]) -> Option<Self> {
+        let rgb =
+            |i: usize| pack_rgb(data[i * 3], data[i * 3 + 1], data[i * 3 + 2]);
+        match data.len() {
+            192 => {
+                let mut colors = [0; 64];
+                for (i, c) in colors.iter_mut().enumerate() {
+                    *c = rgb(i)
+                }
+                Some(Palette::from_colors(&colors))
+            }
+            1536 => {
+                let mut p = Palette {
+                    colors: [0; PALETTE_SIZE],
+                };
+                for (i, c) in p.colors.iter_mut().enumerate() {
+                    *c = rgb(i)
+                }
+                Some(p)
+            }
+            _ => None,
+        }
+    }
+
+    /* generate the palette by decoding the 2C02 composite signal */
+    pub fn from_ntsc(params: &NtscParams) -> Self {
+        let mut p = Palette {
+            colors: [0; PALETTE_SIZE],
+        };
+        for (idx, c) in p.colors.iter_mut().enumerate() {
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let signal = ntsc_signal(idx, phase);
+                let angle = ntsc_angle(phase, params);
+                y += signal;
+                i += signal * math::cos(angle);
+                q += signal * math::sin(angle);
+            }
+            let (r, g, b) = yiq_to_rgb(y / 12.0, i / 12.0, q / 12.0, params);
+            let gamma = |v: f32| to_u8(math::pow(v, 2.2 / params.gamma));
+            *c = pack_rgb(gamma(r), gamma(g), gamma(b))
+        }
+        p
+    }
+
+    /* the palette of the 2C03/2C05 RGB PPUs, where emphasis turns the
+     * channels fully on */
+    pub fn from_rgb_ppu() -> Self {
+        Palette::from_rgb_ppu_remapped(&core::array::from_fn(|i| i as u8))
+    }
+
+    /* the palette of the RP2C04-000`variant` (1 to 4) */
+    pub fn from_rp2c04(variant: u8) -> Option<Self> {
+        match variant {
+            1..=4 => Some(Palette::from_rgb_ppu_remapped(
+                &RP2C04_MAPS[variant as usize - 1],
+            )),
+            _ => None,
+        }
+    }
+
+    /* the 2C04 variants output the master palette in a scrambled order:
+     * `map` gives the master palette entry for each color index */
+    pub fn from_rgb_ppu_remapped(map: &[u8; 64]) -> Self {
+        let mut p = Palette {
+            colors: [0; PALETTE_SIZE],
+        };
+        for (i, c) in p.colors.iter_mut().enumerate() {
+            let rgb = RGB_PPU_COLORS[(map[i & 0x3f] & 0x3f) as usize];
+            let emphasis = i >> 6;
+            let channel = |shift: u16, bit: usize| {
+                if emphasis & bit != 0 {
+                    255
+                } else {
+                    (((rgb >> shift) & 7) * 255 / 7) as u8
+                }
+            };
+            *c = pack_rgb(channel(6, 1), channel(3, 2), channel(0, 4))
+        }
+        p
+    }
+
+    #[inline(always)]
+    pub fn get(&self, color: u16) -> u32 {
+        self.colors[(color & 0x1ff) as usize]
+    }
+
+    #[inline(always)]
+    pub fn get_rgb(&self, color: u16) -> (u8, u8, u8) {
+        let c = self.get(color);
+        ((c >> 16) as u8, (c >> 8) as u8, c as u8)
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::new()
+    }
+}
@@ -1,11 +1,116 @@
 use core::cmp::min;
-use core::mem::{size_of, transmute};
+use core::mem::{offset_of, size_of, transmute};
 
 use crate::memory::{CPUBus, PPUMemory, VMem};
-use crate::utils::{load_prefix, save_prefix, Read, Write};
+use crate::region::Region;
+use crate::utils::{
+    load_prefix, save_prefix, RamInit, RamPattern, Read, Write,
+};
 
+/* OAM decays once rendering has been off for this many lines more than
+ * the normal gap between two frames (about 3000 CPU cycles in total on NTSC) */
+const OAM_DECAY_MARGIN: u16 = 6;
+
+/* the part of the 256x240 picture that is shown, as the pixels cropped
+ * from each side; the fields are only set through the constructors, which
+ * keep the area within the picture */
+#[derive(Copy, Clone, PartialEq)]
+pub struct Overscan {
+    top: u8,
+    bottom: u8,
+    left: u8,
+    right: u8,
+}
+
+impl Overscan {
+    /* at least 8x8 pixels have to be left */
+    pub fn new(top: u8, bottom: u8, left: u8, right: u8) -> Option<Self> {
+        if top as usize + bottom as usize > 232 ||
+            left as usize + right as usize > 248
+        {
+            return None
+        }
+        Some(Overscan {
+            top,
+            bottom,
+            left,
+            right,
+        })
+    }
+
+    pub fn none() -> Self {
+        Overscan {
+            top: 0,
+            bottom: 0,
+            left: 0,
+            right: 0,
+        }
+    }
+
+    /* the lines hidden by most NTSC TVs */
+    pub fn ntsc() -> Self {
+        Overscan {
+            top: 8,
+            bottom: 8,
+            left: 0,
+            right: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn top(&self) -> u8 {
+        self.top
+    }
+
+    #[inline(always)]
+    pub fn bottom(&self) -> u8 {
+        self.bottom
+    }
+
+    #[inline(always)]
+    pub fn left(&self) -> u8 {
+        self.left
+    }
+
+    #[inline(always)]
+    pub fn right(&self) -> u8 {
+        self.right
+    }
+
+    #[inline(always)]
+    pub fn width(&self) -> usize {
+        256 - self.left as usize - self.right as usize
+    }
+
+    #[inline(always)]
+    pub fn height(&self) -> usize {
+        240 - self.top as usize - self.bottom as usize
+    }
+}
+
+/* The picture is delivered one finished scanline at a time through
+ * `put_line`, which by default hands out the pixels to `put`; a frontend
+ * may override `put_line` to take the whole line at once, or give the PPU
+ * its own buffer to draw into with `frame_buffer`. Only the area left by
+ * `overscan` is delivered, with the coordinates starting at its top-left
+ * corner. */
 pub trait Screen {
-    fn put(&mut self, x: u8, y: u8, color: u8);
+    /* color is a 9-bit index: the 6-bit palette color in bits 0..5 and the
+     * emphasis bits of PPUMASK (red, green, blue) in bits 6..8 */
+    fn put(&mut self, x: u8, y: u8, color: u16);
+    fn put_line(&mut self, y: u8, line: &[u16]) {
+        for (x, &color) in line.iter().enumerate() {
+            self.put(x as u8, y, color)
+        }
+    }
+    /* a buffer of overscan width x height that receives the scanlines
+     * instead of `put_line` */
+    fn frame_buffer(&mut self) -> Option<&mut [u16]> {
+        None
+    }
+    fn overscan(&self) -> Overscan {
+        Overscan::none()
+    }
     fn render(&mut self);
     fn frame(&mut self);
 }
@@ -19,6 +124,37 @@ struct Sprite {
     x: u8,
 }
 
+/* a copy of the PPU registers and rendering latches for debuggers and
+ * tests, cheap enough to be taken at every dot */
+#[derive(Copy, Clone)]
+pub struct PpuState {
+    pub scanline: u16,
+    pub cycle: u16,
+    pub frame: u32,
+    pub ppuctl: u8,
+    pub ppumask: u8,
+    pub ppustatus: u8,
+    pub oamaddr: u8,
+    pub v: u16,  /* current vram addr */
+    pub t: u16,  /* temporary vram addr */
+    pub x: u8,   /* fine x scroll */
+    pub w: bool, /* first/second write toggle */
+    pub odd_frame: bool,
+    /* background shifters (4 bits per pixel: attribute and pattern) and
+     * the latches for the next tile */
+    pub bg_pixel: u64,
+    pub bg_nt: u8,
+    pub bg_attr: u8,
+    pub bg_bit_low: u8,
+    pub bg_bit_high: u8,
+    pub oam: [u8; 256],
+    pub oam2: [u8; 32], /* secondary OAM */
+    /* sprite pixels of the current scanline: 0xffff if transparent, else
+     * bit 15 for sprite 0, bit 8 for priority and the palette index */
+    pub sp_cache: [u16; 256],
+    pub buffered_read: u8, /* the $2007 read buffer */
+}
+
 #[repr(C)]
 pub struct PPU<'a> {
     /*-- begin state --*/
@@ -45,23 +181,60 @@ pub struct PPU<'a> {
     bg_bit_high: u8,
     /* sprites */
     oam: [Sprite; 64],
-    oam2: [u8; 8],
+    oam2: [u8; 32], /* secondary OAM */
+    oam_latch: u8,  /* last value read by the sprite evaluation */
+    /* sprite evaluation */
+    sp_n: u8,    /* index of the sprite in OAM */
+    sp_m: u8,    /* index of the byte in the sprite */
+    sp_n0: u8,   /* the first sprite checked (sprite "zero") */
+    sp_sec: u8,  /* write address of the secondary OAM */
+    sp_left: u8, /* bytes of the overflowing sprite still to be read */
+    sp_in_range: bool,
+    sp_done: bool, /* all sprites have been checked */
+    sp0_added: bool,
+    sp0_hit: bool, /* sprite zero hit to be raised on the next dot */
+    sp_low: u8,    /* low pattern byte of the sprite being fetched */
     sp_cache: [u16; 256], /* pre-computed sp value */
     vblank: bool,
     pub vblank_lines: bool,
     buffered_read: u8,
     early_read: bool,
+    pub frame: u32, /* number of frames rendered since power-up */
+    /* after power-up or reset, writes to $2000, $2001, $2005 and $2006 are
+     * ignored until the pre-render line */
+    warmup: bool,
+    /* region timing */
+    pre_line: u16, /* the last scanline of the frame */
+    vbl_line: u16, /* the scanline where vblank starts */
+    odd_skip: bool,
+    oam_decay_dots: u64, /* PPU dots without refresh before OAM decays */
+    oam_corrupt: u32,    /* rows to be overwritten by row 0 (one bit per row) */
+    oam_row_dot: [u64; 32], /* when each row of 8 bytes was last accessed */
+    oam_refresh_dot: u64, /* when rendering last refreshed the whole OAM */
     /*-- end state --*/
 
     /*-- begin sub-state --*/
     mem: PPUMemory<'a>,
     /*-- end sub-state --*/
     pub scr: &'a mut dyn Screen,
+    /* the scanline being drawn, only output: a state saved mid-line gives
+     * back that line partly drawn */
+    line: [u16; 256],
+    /* user settings, not saved with the state */
+    /* enhancement: draw all the sprites of a scanline instead of the first
+     * eight (overflow is still reported as on hardware) */
+    pub no_sprite_limit: bool,
+    /* don't compose nor deliver the picture (fast-forward, run-ahead);
+     * everything else, including sprite 0 hits, runs as usual */
+    pub skip_output: bool,
+    /* accuracy option: OAM decay and corruption */
+    pub oam_glitches: bool,
 }
 
 macro_rules! PPU_IGNORED_SIZE {
     () => {
-        size_of::<PPUMemory>() + size_of::<&mut dyn Screen>()
+        /* everything from mem on, including the trailing padding */
+        size_of::<PPU>() - offset_of!(PPU, mem)
     };
 }
 
@@ -69,6 +242,9 @@ impl<'a> PPU<'a> {
     #[inline]
     pub fn write_ctl(&mut self, data: u8) {
         self.reg = data;
+        if self.warmup {
+            return
+        }
         self.ppuctl = data;
         self.t = (self.t & 0x73ff) | ((data as u16 & 3) << 10);
     }
@@ -76,7 +252,18 @@ impl<'a> PPU<'a> {
     #[inline]
     pub fn write_mask(&mut self, data: u8) {
         self.reg = data;
+        if self.warmup {
+            return
+        }
+        let rendering = self.get_show_bg() || self.get_show_sp();
         self.ppumask = data;
+        if self.oam_glitches &&
+            rendering &&
+            !(self.get_show_bg() || self.get_show_sp()) &&
+            (self.scanline < 240 || self.scanline == self.pre_line)
+        {
+            self.set_oam_corrupt()
+        }
     }
 
     #[inline]
@@ -84,7 +271,7 @@ impl<'a> PPU<'a> {
         let res = (self.ppustatus & !0x1fu8) | (self.reg & 0x1f);
         self.ppustatus &= !PPU::FLAG_VBLANK;
         self.w = false;
-        if self.scanline == 241 && self.cycle == 1 {
+        if self.scanline == self.vbl_line && self.cycle == 1 {
             self.early_read = true /* read before cycle 1 */
         }
         res
@@ -99,19 +286,99 @@ impl<'a> PPU<'a> {
     #[inline]
     pub fn write_oamdata(&mut self, data: u8) {
         self.reg = data;
+        let rendering = self.get_show_bg() || self.get_show_sp();
+        if self.oam_glitches &&
+            rendering &&
+            (self.scanline < 240 || self.scanline == self.pre_line)
+        {
+            /* no write, but a glitchy increment that only bumps the sprite
+             * index */
+            self.oamaddr = self.oamaddr.wrapping_add(4);
+            return
+        }
         let addr = self.oamaddr as usize;
-        self.get_oam_raw_mut()[addr] = data;
+        self.decay_oam_row(addr >> 3);
+        /* bits 2-4 of the attribute byte do not exist */
+        self.get_oam_raw_mut()[addr] =
+            if addr & 3 == 2 { data & 0xe3 } else { data };
         self.oamaddr = self.oamaddr.wrapping_add(1);
     }
 
     #[inline]
-    pub fn read_oamdata(&self) -> u8 {
-        self.get_oam_raw()[self.oamaddr as usize]
+    pub fn read_oamdata(&mut self) -> u8 {
+        let rendering = self.get_show_bg() || self.get_show_sp();
+        if rendering && (self.scanline < 240 || self.scanline == self.pre_line)
+        {
+            /* the bus is busy with sprite evaluation and fetches */
+            self.oam_latch
+        } else {
+            self.decay_oam_row(self.oamaddr as usize >> 3);
+            self.get_oam_raw()[self.oamaddr as usize]
+        }
+    }
+
+    /* OAM is DRAM refreshed by rendering: a row that has been left alone
+     * for too long with rendering disabled loses its contents; the values
+     * vary between consoles, all bits set keeps the sprites hidden */
+    fn decay_oam_row(&mut self, row: usize) {
+        if !self.oam_glitches {
+            return
+        }
+        let dot = self.mem.get_dot();
+        let last = self.oam_row_dot[row].max(self.oam_refresh_dot);
+        if dot - last > self.oam_decay_dots {
+            for b in self.get_oam_raw_mut()[row << 3..(row + 1) << 3].iter_mut()
+            {
+                *b = 0xff
+            }
+        }
+        self.oam_row_dot[row] = dot
+    }
+
+    /* disabling rendering mid-scanline leaves the OAM row selected by the
+     * secondary OAM address to be overwritten by row 0 once rendering is
+     * enabled again */
+    fn set_oam_corrupt(&mut self) {
+        let cycle = self.cycle;
+        let addr = match cycle {
+            1..=64 => (cycle - 1) >> 1,
+            65..=256 => self.sp_sec as u16 & 0x1f,
+            257..=320 => {
+                let c = cycle - 257;
+                ((c >> 3) << 2) | min(c & 7, 3)
+            }
+            _ => return,
+        };
+        self.oam_corrupt |= 1 << addr
+    }
+
+    fn apply_oam_glitches(&mut self) {
+        let dot = self.mem.get_dot();
+        if dot - self.oam_refresh_dot > self.oam_decay_dots {
+            /* rendering is resumed after a long pause */
+            for row in 0..32 {
+                self.decay_oam_row(row)
+            }
+        }
+        self.oam_refresh_dot = dot;
+        let corrupt = self.oam_corrupt;
+        if corrupt != 0 {
+            let oam = self.get_oam_raw_mut();
+            for row in 1..32 {
+                if corrupt & (1 << row) != 0 {
+                    oam.copy_within(0..8, row << 3)
+                }
+            }
+            self.oam_corrupt = 0
+        }
     }
 
     #[inline]
     pub fn write_scroll(&mut self, data: u8) {
         self.reg = data;
+        if self.warmup {
+            return
+        }
         let data = data as u16;
         match self.w {
             false => {
@@ -131,6 +398,9 @@ impl<'a> PPU<'a> {
     #[inline]
     pub fn write_addr(&mut self, data: u8) {
         self.reg = data;
+        if self.warmup {
+            return
+        }
         let data = data as u16;
         match self.w {
             false => {
@@ -141,6 +411,7 @@ impl<'a> PPU<'a> {
                 self.t = (self.t & 0xff00) | data;
                 self.v = self.t;
                 self.w = false;
+                self.mem.put_addr(self.v)
             }
         }
     }
@@ -154,7 +425,11 @@ impl<'a> PPU<'a> {
             prev
         } else {
             self.buffered_read = self.mem.read(self.v - 0x1000);
-            data
+            if self.get_grayscale() {
+                data & 0x30
+            } else {
+                data
+            }
         };
         self.v = self.v.wrapping_add(match self.get_vram_inc() {
             0 => 1,
@@ -173,28 +448,6 @@ impl<'a> PPU<'a> {
         });
     }
 
-    #[inline]
-    pub fn write_oamdma(&mut self, data: u8, bus: &CPUBus) {
-        let cpu = bus.get_cpu();
-        self.reg = data;
-        let mut addr = (data as u16) << 8;
-        let stall = 1 + (cpu.cycle & 1) + 512;
-        bus.cpu_stall(stall);
-        let mut oamaddr = self.oamaddr;
-        for _ in 0..stall - 0x100 {
-            cpu.mem.bus.tick()
-        }
-        {
-            let oam_raw = self.get_oam_raw_mut();
-            for _ in 0..0x100 {
-                oam_raw[oamaddr as usize] = cpu.mem.read_without_tick(addr);
-                addr = addr.wrapping_add(1);
-                oamaddr = oamaddr.wrapping_add(1);
-            }
-        }
-        self.oamaddr = oamaddr;
-    }
-
     #[inline(always)]
     fn get_spritesize(&self) -> u8 {
         (self.ppuctl >> 5) & 1
@@ -208,6 +461,10 @@ impl<'a> PPU<'a> {
         (self.ppuctl >> 2) & 1
     }
     #[inline(always)]
+    fn get_grayscale(&self) -> bool {
+        self.ppumask & 1 == 1
+    }
+    #[inline(always)]
     fn get_show_leftmost_bg(&self) -> bool {
         (self.ppumask >> 1) & 1 == 1
     }
@@ -224,12 +481,12 @@ impl<'a> PPU<'a> {
         (self.ppumask >> 4) & 1 == 1
     }
     #[inline(always)]
-    fn get_flag_vblank(&self) -> bool {
-        (self.ppustatus >> 7) & 1 == 1
+    pub fn get_flag_sprite_zero(&self) -> bool {
+        (self.ppustatus >> 6) & 1 == 1
     }
     #[inline(always)]
-    fn get_oam_arr(&self) -> &[[u8; 4]; 64] {
-        unsafe { transmute::<&[Sprite; 64], &[[u8; 4]; 64]>(&self.oam) }
+    fn get_flag_vblank(&self) -> bool {
+        (self.ppustatus >> 7) & 1 == 1
     }
     #[inline(always)]
     fn get_oam_raw_mut(&mut self) -> &mut [u8; 256] {
@@ -346,47 +603,103 @@ impl<'a> PPU<'a> {
 
     #[inline(always)]
     fn clear_sprite(&mut self) {
-        debug_assert!(self.scanline != 261);
-        self.oam2 = [0xff; 8];
+        debug_assert!(self.scanline != self.pre_line);
+        /* done over cycles 1..64, during which $2004 reads give 0xff */
+        self.oam2 = [0xff; 32];
+        self.oam_latch = 0xff;
     }
 
-    fn eval_sprite(&mut self) {
-        debug_assert!(self.scanline != 261);
+    #[inline(always)]
+    fn in_range(&self, y: u8) -> bool {
         /* we use scanline here because s.y is the (actual y) - 1 */
-        let mut nidx = 0;
-        let mut n = 0;
-        let scanline = self.scanline;
         let h = match self.get_spritesize() {
             0 => 8,
             _ => 16,
         };
-        for (i, s) in self.oam.iter().enumerate() {
-            let y = s.y as u16;
-            if y <= scanline && scanline < y + h {
-                self.oam2[nidx] = i as u8;
-                nidx += 1;
-                if nidx == 8 {
-                    n = i + 1;
-                    break
-                }
+        let y = y as u16;
+        y <= self.scanline && self.scanline < y + h
+    }
+
+    /* one cycle of sprite evaluation (65..256): read OAM on odd cycles and
+     * write to the secondary OAM on even cycles */
+    fn eval_sprite(&mut self) {
+        debug_assert!(self.scanline != self.pre_line);
+        if self.cycle == 65 {
+            self.sp_n = self.oamaddr >> 2;
+            self.sp_m = self.oamaddr & 3;
+            self.sp_n0 = self.sp_n;
+            self.sp_sec = 0;
+            self.sp_left = 0;
+            self.sp_in_range = false;
+            self.sp_done = false;
+            self.sp0_added = false;
+        }
+        if self.cycle & 1 == 1 {
+            let addr = (self.sp_n << 2) | self.sp_m;
+            self.oam_latch = self.get_oam_raw()[addr as usize];
+            return
+        }
+        let data = self.oam_latch;
+        if self.sp_done {
+            self.sp_n = (self.sp_n + 1) & 0x3f;
+            if self.sp_sec >= 0x20 {
+                /* writes to the secondary OAM turn into reads */
+                self.oam_latch = self.oam2[(self.sp_sec & 0x1f) as usize]
             }
+            return
         }
-        if nidx == 8 {
-            let mut m = 0;
-            let mut ppustatus = self.ppustatus;
-            {
-                let oam_raw = self.get_oam_arr();
-                while n < 64 {
-                    let y = oam_raw[n][m] as u16;
-                    if y <= scanline && scanline < y + h {
-                        ppustatus |= PPU::FLAG_OVERFLOW; /* set overflow */
-                    } else {
-                        m = (m + 1) & 3; /* emulates hardware bug */
-                    }
-                    n += 1;
+        if !self.sp_in_range && self.in_range(data) {
+            self.sp_in_range = true
+        }
+        if self.sp_sec < 0x20 {
+            self.oam2[self.sp_sec as usize] = data;
+            if self.sp_in_range {
+                if self.sp_n == self.sp_n0 {
+                    self.sp0_added = true
+                }
+                self.sp_sec += 1;
+                self.sp_m += 1;
+                if self.sp_m == 4 {
+                    self.sp_in_range = false;
+                    self.sp_m = 0;
+                    self.next_sprite()
                 }
+            } else {
+                self.next_sprite()
+            }
+        } else {
+            self.oam_latch = self.oam2[(self.sp_sec & 0x1f) as usize];
+            if self.sp_in_range {
+                /* set overflow and read the next 3 bytes of OAM, even when
+                 * the match was not on the first byte of a sprite */
+                if self.sp_left == 0 {
+                    self.ppustatus |= PPU::FLAG_OVERFLOW;
+                    self.sp_left = 3
+                } else {
+                    self.sp_left -= 1
+                }
+                self.sp_m = (self.sp_m + 1) & 3;
+                if self.sp_m == 0 {
+                    self.sp_n = (self.sp_n + 1) & 0x3f
+                }
+                if self.sp_left == 0 {
+                    self.sp_m = 0;
+                    self.sp_done = true
+                }
+            } else {
+                /* hardware bug: m is also incremented, so the following
+                 * checks are done against the wrong byte of the sprite */
+                self.sp_m = (self.sp_m + 1) & 3;
+                self.next_sprite()
             }
-            self.ppustatus = ppustatus;
+        }
+    }
+
+    #[inline(always)]
+    fn next_sprite(&mut self) {
+        self.sp_n = (self.sp_n + 1) & 0x3f;
+        if self.sp_n == 0 {
+            self.sp_done = true
         }
     }
 
@@ -398,60 +711,111 @@ impl<'a> PPU<'a> {
         x
     }
 
+    /* one cycle of a sprite fetch slot: two garbage nametable fetches
+     * followed by the two pattern fetches of the sprite */
     fn fetch_sprite(&mut self) {
-        if self.scanline == 261 {
-            return
+        let c = self.cycle - 257;
+        let slot = (c >> 3) as usize;
+        let base = slot << 2;
+        /* $2004 reads return what the PPU is fetching from secondary OAM */
+        self.oam_latch = self.oam2[base | min(c as usize & 7, 3)];
+        let (y, tile, attr, x) = (
+            self.oam2[base],
+            self.oam2[base | 1],
+            self.oam2[base | 2],
+            self.oam2[base | 3],
+        );
+        if c == 0 {
+            self.sp_cache = [0xffff; 256]
         }
-        /* we use scanline here because s.y is the (actual y) - 1 */
-        self.sp_cache = [0xffff; 256];
-        for &j in self.oam2.iter() {
-            if j == 0xff {
-                break
-            }
-            let s = &self.oam[j as usize];
-            let vflip = (s.attr & 0x80) == 0x80;
-            let y0 = self.scanline - s.y as u16;
-            let (ptable, tidx, y) = match self.get_spritesize() {
-                0 => {
-                    let y = if vflip { 7 - y0 as u8 } else { y0 as u8 };
-                    ((self.ppuctl as u16 & 0x08) << 9, s.tile, y)
+        /* the row is meaningless for the empty slots and on the pre-render
+         * line, but the fetches still happen */
+        let addr = self.sprite_addr(y, tile, attr);
+        match c & 7 {
+            0 | 2 => {
+                self.mem.read_nametable(self.v & 0x0fff);
+            }
+            4 => self.sp_low = self.mem.read_mapper(addr),
+            6 => {
+                let high = self.mem.read_mapper(addr | 0x8);
+                if self.scanline != self.pre_line &&
+                    slot < (self.sp_sec >> 2) as usize
+                {
+                    self.put_sprite(slot, attr, x, self.sp_low, high)
                 }
-                _ => {
-                    let y = if vflip { 15 - y0 as u8 } else { y0 as u8 };
-                    (
-                        (s.tile as u16 & 1) << 12,
-                        (s.tile & !1u8) | (y >> 3),
-                        y & 0x7,
-                    )
+                if c == 62 &&
+                    self.no_sprite_limit &&
+                    self.scanline != self.pre_line &&
+                    self.sp_sec >= 0x20
+                {
+                    self.put_extra_sprites()
                 }
-            };
-            let mut low = self
-                .mem
-                .read_mapper(ptable | ((tidx as u16) << 4) | 0x0 | y as u16);
-            let mut high = self
-                .mem
-                .read_mapper(ptable | ((tidx as u16) << 4) | 0x8 | y as u16);
-            if (s.attr & 0x40) == 0x40 {
-                low = PPU::reverse_byte(low);
-                high = PPU::reverse_byte(high);
-            }
-            let attr = s.attr & 3;
-            let x_max = min(s.x as usize + 8, 256);
-            /* pre-compute sprite pixels */
-            for p in (&mut self.sp_cache[s.x as usize..x_max]).iter_mut().rev()
-            {
-                if *p == 0xffff {
-                    let sp =
-                        ((attr << 2) | ((high & 1) << 1) | (low & 1)) as u16;
-                    if sp & 3 != 0x0 {
-                        *p = ((if j == 0 {1} else {0}) << 15) | /* if zero sprite */
-                             (((s.attr >> 5) as u16 & 1) << 8) | /* priority flag */
-                             sp;
-                    }
+            }
+            _ => (),
+        }
+    }
+
+    /* pattern table address of the sprite row for the next scanline */
+    fn sprite_addr(&self, y: u8, tile: u8, attr: u8) -> u16 {
+        /* we use scanline here because s.y is the (actual y) - 1 */
+        let vflip = (attr & 0x80) == 0x80;
+        let y0 = self.scanline.wrapping_sub(y as u16) as u8;
+        let (ptable, tidx, y) = match self.get_spritesize() {
+            0 => {
+                let y = y0 & 7;
+                let y = if vflip { 7 - y } else { y };
+                ((self.ppuctl as u16 & 0x08) << 9, tile, y)
+            }
+            _ => {
+                let y = y0 & 15;
+                let y = if vflip { 15 - y } else { y };
+                ((tile as u16 & 1) << 12, (tile & !1u8) | (y >> 3), y & 0x7)
+            }
+        };
+        ptable | ((tidx as u16) << 4) | y as u16
+    }
+
+    /* add the in-range sprites after the first eight, behind them; the
+     * patterns are peeked so that the mapper does not see the extra
+     * fetches */
+    fn put_extra_sprites(&mut self) {
+        let mut found = 0;
+        for i in 0..64 {
+            let s = self.oam[(self.sp_n0 as usize + i) & 0x3f];
+            if !self.in_range(s.y) {
+                continue
+            }
+            found += 1;
+            if found > 8 {
+                let addr = self.sprite_addr(s.y, s.tile, s.attr);
+                let low = self.mem.peek_mapper(addr);
+                let high = self.mem.peek_mapper(addr | 0x8);
+                self.put_sprite(8, s.attr, s.x, low, high)
+            }
+        }
+    }
+
+    /* pre-compute the pixels of the sprite for the next scanline */
+    fn put_sprite(&mut self, slot: usize, sattr: u8, x: u8, low: u8, high: u8) {
+        let (mut low, mut high) = if (sattr & 0x40) == 0x40 {
+            (PPU::reverse_byte(low), PPU::reverse_byte(high))
+        } else {
+            (low, high)
+        };
+        let attr = sattr & 3;
+        let sp0 = slot == 0 && self.sp0_added;
+        let x_max = min(x as usize + 8, 256);
+        for p in self.sp_cache[x as usize..x_max].iter_mut().rev() {
+            if *p == 0xffff {
+                let sp = ((attr << 2) | ((high & 1) << 1) | (low & 1)) as u16;
+                if sp & 3 != 0x0 {
+                    *p = ((if sp0 {1} else {0}) << 15) | /* if zero sprite */
+                         (((sattr >> 5) as u16 & 1) << 8) | /* priority flag */
+                         sp;
                 }
-                high >>= 1;
-                low >>= 1;
             }
+            high >>= 1;
+            low >>= 1;
         }
     }
 
@@ -473,40 +837,105 @@ impl<'a> PPU<'a> {
             let p = self.sp_cache[x as usize];
             if p != 0xffff {
                 if (p >> 15 == 1) && bg_pidx != 0 && x != 0xff {
-                    self.ppustatus |= PPU::FLAG_SPRITE_ZERO; /* set sprite zero hit */
+                    /* hits act as if the picture started at dot 2: the
+                     * flag for pixel x is raised at dot x + 2 */
+                    self.sp0_hit = true
                 }
                 pri = (p >> 8) & 1;
                 sp = p & 0x00ff;
                 sp_pidx = sp & 3;
             }
         }
+        if self.skip_output {
+            return
+        }
         debug_assert!(0 < self.cycle && self.cycle < 257);
         debug_assert!(self.scanline < 240);
-        self.scr.put(
-            (self.cycle - 1) as u8,
-            self.scanline as u8,
-            self.mem.read_palette(
-                if (pri == 0 || bg_pidx == 0) && sp_pidx != 0 {
-                    0x0010 | sp
-                } else {
-                    0x0000 |
-                        match bg_pidx {
-                            0 => 0,
-                            _ => bg,
-                        }
-                },
-            ) & 0x3f,
+        let color = self.mem.read_palette(
+            if (pri == 0 || bg_pidx == 0) && sp_pidx != 0 {
+                0x0010 | sp
+            } else {
+                0x0000 |
+                    match bg_pidx {
+                        0 => 0,
+                        _ => bg,
+                    }
+            },
         );
+        self.put_color(color);
+    }
+
+    /* with rendering disabled, the backdrop color is shown, unless v points
+     * to the palette, in which case that color is shown instead */
+    fn render_backdrop(&mut self) {
+        let addr = if self.v & 0x3f00 == 0x3f00 {
+            self.v & 0x1f
+        } else {
+            0
+        };
+        let color = self.mem.read_palette(addr);
+        self.put_color(color);
+    }
+
+    #[inline(always)]
+    fn put_color(&mut self, color: u8) {
+        let mut color = color & 0x3f;
+        if self.get_grayscale() {
+            color &= 0x30
+        }
+        self.line[(self.cycle - 1) as usize] =
+            color as u16 | ((self.ppumask as u16 & 0xe0) << 1)
+    }
+
+    fn flush_line(&mut self) {
+        let o = self.scr.overscan();
+        let y = self.scanline as usize;
+        if y < o.top as usize || y >= 240 - o.bottom as usize {
+            return
+        }
+        let y = y - o.top as usize;
+        let (w, left) = (o.width(), o.left as usize);
+        let line = &self.line[left..left + w];
+        match self.scr.frame_buffer() {
+            Some(fb) => fb[y * w..(y + 1) * w].copy_from_slice(line),
+            None => self.scr.put_line(y as u8, line),
+        }
+    }
+
+    pub fn get_state(&self) -> PpuState {
+        PpuState {
+            scanline: self.scanline,
+            cycle: self.cycle,
+            frame: self.frame,
+            ppuctl: self.ppuctl,
+            ppumask: self.ppumask,
+            ppustatus: self.ppustatus,
+            oamaddr: self.oamaddr,
+            v: self.v,
+            t: self.t,
+            x: self.x,
+            w: self.w,
+            odd_frame: self.f,
+            bg_pixel: self.bg_pixel,
+            bg_nt: self.bg_nt,
+            bg_attr: self.bg_attr,
+            bg_bit_low: self.bg_bit_low,
+            bg_bit_high: self.bg_bit_high,
+            oam: *self.get_oam_raw(),
+            oam2: self.oam2,
+            sp_cache: self.sp_cache,
+            buffered_read: self.buffered_read,
+        }
     }
 
     pub fn new(mem: PPUMemory<'a>, scr: &'a mut dyn Screen) -> Self {
         let ppuctl = 0x00;
         let ppumask = 0x00;
-        let ppustatus = 0xa0;
+        let ppustatus = 0x20; /* the PPU starts at the top, not in vblank */
         let oamaddr = 0x00;
         let buffered_read = 0x00;
         let cycle = 0;
-        let scanline = 241;
+        let scanline = 0;
         PPU {
             scanline,
             ppuctl,
@@ -518,7 +947,7 @@ impl<'a> PPU<'a> {
             v: 0,
             t: 0,
             w: false,
-            f: true,
+            f: false,
             cycle,
             bg_pixel: 0,
             bg_nt: 0,
@@ -531,12 +960,36 @@ impl<'a> PPU<'a> {
                 attr: 0,
                 x: 0,
             }; 64],
-            oam2: [0xff; 8],
+            oam2: [0xff; 32],
+            oam_latch: 0xff,
+            sp_n: 0,
+            sp_m: 0,
+            sp_n0: 0,
+            sp_sec: 0,
+            sp_left: 0,
+            sp_in_range: false,
+            sp_done: false,
+            sp0_added: false,
+            sp0_hit: false,
+            sp_low: 0,
             sp_cache: [0xffff; 256],
+            line: [0; 256],
             vblank: false,
-            vblank_lines: true,
+            vblank_lines: false,
             buffered_read,
             early_read: false,
+            frame: 0,
+            warmup: true,
+            pre_line: 261,
+            vbl_line: 241,
+            odd_skip: true,
+            no_sprite_limit: false,
+            skip_output: false,
+            oam_glitches: false,
+            oam_corrupt: 0,
+            oam_row_dot: [0; 32],
+            oam_refresh_dot: 0,
+            oam_decay_dots: PPU::decay_threshold(261),
             mem,
             scr,
         }
@@ -550,16 +1003,49 @@ impl<'a> PPU<'a> {
         save_prefix(self, PPU_IGNORED_SIZE!(), writer) && self.mem.save(writer)
     }
 
-    #[allow(dead_code)]
+    /* randomize the power-on contents of OAM, palette and nametables; with a
+     * random pattern, the PPU also starts at an arbitrary dot within the
+     * CPU cycle, as the CPU/PPU clock alignment is not fixed on hardware */
+    pub fn powerup(&mut self, init: &mut RamInit) {
+        init.fill(self.get_oam_raw_mut());
+        self.mem.powerup(init);
+        if let RamPattern::Random(_) = init.get_pattern() {
+            self.cycle += (init.rand() % 3) as u16
+        }
+    }
+
+    /* rendering leaves OAM alone from line 240 to the pre-render line */
+    fn decay_threshold(pre_line: u16) -> u64 {
+        (pre_line - 240 + OAM_DECAY_MARGIN) as u64 * 341
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.pre_line = region.scanlines() - 1;
+        self.vbl_line = region.vblank_line();
+        self.odd_skip = region.odd_frame_skip();
+        self.oam_decay_dots = PPU::decay_threshold(self.pre_line);
+        if self.scanline > self.pre_line {
+            self.scanline = self.pre_line
+        }
+    }
+
+    /* the reset button: unlike power-up, OAM, OAMADDR, v and the status
+     * flags are kept; rendering starts over from the top of the picture */
     pub fn reset(&mut self) {
         self.ppuctl = 0x00;
         self.ppumask = 0x00;
-        self.ppustatus = self.ppustatus & 0x80;
+        self.t = 0;
+        self.x = 0;
         self.w = false;
+        self.f = false;
         self.buffered_read = 0x00;
         self.cycle = 0;
-        self.scanline = 241;
-        self.vblank_lines = true;
+        self.scanline = 0;
+        self.vblank = false;
+        self.vblank_lines = false;
+        self.early_read = false;
+        self.sp0_hit = false;
+        self.warmup = true;
     }
 
     #[inline(always)]
@@ -575,75 +1061,88 @@ impl<'a> PPU<'a> {
 
     fn _tick(&mut self) -> bool {
         let cycle = self.cycle;
+        if self.sp0_hit {
+            self.ppustatus |= PPU::FLAG_SPRITE_ZERO;
+            self.sp0_hit = false
+        }
         if cycle == 0 {
             self.cycle = 1;
-            if self.scanline == 240 {
+            if self.scanline == self.vbl_line - 1 {
                 self.vblank_lines = true
-            } else if self.scanline == 261 {
+            } else if self.scanline == self.pre_line {
                 self.vblank_lines = false
             }
             return false
         }
         let rendering = self.get_show_bg() || self.get_show_sp();
         let visible_line = self.scanline < 240;
-        let pre_line = self.scanline == 261;
+        let pre_line = self.scanline == self.pre_line;
         if (pre_line || visible_line) && rendering {
+            if self.oam_glitches {
+                self.apply_oam_glitches()
+            }
             if pre_line && 279 < cycle && cycle < 305 {
                 self.reset_y();
-            } else {
-                let visible_cycle = 0 < cycle && cycle < 257; /* 1..256 */
-                let prefetch_cycle = 320 < cycle && cycle < 337;
-                let fetch_cycle = visible_cycle || prefetch_cycle;
-                if (visible_line && fetch_cycle) || (pre_line && prefetch_cycle)
-                {
-                    match cycle & 0x7 {
-                        1 => {
-                            self.load_bgtile();
-                            self.fetch_nametable_byte();
-                        }
-                        3 => self.fetch_attrtable_byte(),
-                        5 => self.fetch_low_bgtile_byte(),
-                        7 => self.fetch_high_bgtile_byte(),
-                        0 => self.wrapping_inc_cx(),
-                        _ => (),
-                    }
-                    match cycle {
-                        1 => self.clear_sprite(), /* clear secondary OAM */
-                        65 => self.eval_sprite(), /* sprite evaluation */
-                        256 => self.wrapping_inc_y(),
-                        _ => (),
-                    }
-                    if visible_cycle {
-                        self.render_pixel();
+            }
+            let visible_cycle = 0 < cycle && cycle < 257; /* 1..256 */
+            let prefetch_cycle = 320 < cycle && cycle < 337;
+            let fetch_cycle = visible_cycle || prefetch_cycle;
+            if (visible_line && fetch_cycle) || (pre_line && prefetch_cycle) {
+                match cycle & 0x7 {
+                    1 => {
+                        self.load_bgtile();
+                        self.fetch_nametable_byte();
                     }
-                    self.shift_bgtile();
-                } else if cycle == 257 {
-                    /* we don't emulate fetch to per cycle precision because all data are fetched
-                     * from the secondary OAM which is not subject to any change during this
-                     * scanline */
-                    self.reset_cx();
-                    self.fetch_sprite();
-                    self.cycle = 258;
-                    return false
+                    3 => self.fetch_attrtable_byte(),
+                    5 => self.fetch_low_bgtile_byte(),
+                    7 => self.fetch_high_bgtile_byte(),
+                    0 => self.wrapping_inc_cx(),
+                    _ => (),
+                }
+                match cycle {
+                    1 => self.clear_sprite(), /* clear secondary OAM */
+                    65..=256 => self.eval_sprite(), /* sprite evaluation */
+                    _ => (),
                 }
-                /* skip at 338 because of 10-even_odd_timing test indicates an undocumented
-                 * behavior of NES */
-                if pre_line && cycle == 338 && self.f {
-                    self.cycle = 340;
-                    return false
+                if cycle == 256 {
+                    self.wrapping_inc_y()
                 }
+                if visible_cycle {
+                    self.render_pixel();
+                }
+                self.shift_bgtile();
+            } else if 256 < cycle && cycle < 321 {
+                /* 257..320: eight 8-cycle sprite fetch slots */
+                if cycle == 257 {
+                    self.reset_cx();
+                }
+                self.oamaddr = 0;
+                self.fetch_sprite();
+            }
+            /* skip at 338 because of 10-even_odd_timing test indicates an
+             * undocumented behavior of NES */
+            if pre_line && cycle == 338 && self.f && self.odd_skip {
+                self.cycle = 340;
+                return false
             }
         } else {
             if !rendering {
-                self.bg_pixel = 0
+                self.bg_pixel = 0;
+                if visible_line && 0 < cycle && cycle < 257 && !self.skip_output
+                {
+                    self.render_backdrop()
+                }
             }
-            if self.scanline == 241 && self.cycle == 1 {
+            if self.scanline == self.vbl_line && self.cycle == 1 {
                 if !self.early_read {
                     self.ppustatus |= PPU::FLAG_VBLANK
                 }
                 self.early_read = false;
                 self.vblank = true;
-                self.scr.render();
+                self.frame = self.frame.wrapping_add(1);
+                if !self.skip_output {
+                    self.scr.render()
+                }
                 self.scr.frame();
                 self.cycle = 2;
                 return self.try_nmi()
@@ -652,6 +1151,7 @@ impl<'a> PPU<'a> {
         if pre_line && cycle == 1 {
             /* clear vblank, sprite zero hit & overflow */
             self.vblank = false;
+            self.warmup = false;
             self.ppustatus &= !(PPU::FLAG_VBLANK |
                 PPU::FLAG_SPRITE_ZERO |
                 PPU::FLAG_OVERFLOW);
@@ -659,11 +1159,14 @@ impl<'a> PPU<'a> {
             self.cycle = 2;
             return false
         }
+        if cycle == 256 && visible_line && !self.skip_output {
+            self.flush_line()
+        }
         self.cycle += 1;
         if self.cycle > 340 {
             self.cycle = 0;
             self.scanline += 1;
-            if self.scanline > 261 {
+            if self.scanline > self.pre_line {
                 self.scanline = 0;
                 self.f = !self.f;
             }
@@ -671,3 +1174,163 @@ impl<'a> PPU<'a> {
         false
     }
 }
+
+/* debug views of the PPU memory, rendered as 6-bit color indices (to be
+ * looked up in a `Palette`); they peek the memory, so neither the PPU nor the
+ * mapper is affected */
+pub const PATTERN_VIEW_WIDTH: usize = 256; /* $0000 and $1000 side by side */
+pub const PATTERN_VIEW_HEIGHT: usize = 128;
+pub const NAMETABLE_VIEW_WIDTH: usize = 512; /* $2000 $2400 */
+pub const NAMETABLE_VIEW_HEIGHT: usize = 480; /* $2800 $2C00 */
+pub const OAM_VIEW_WIDTH: usize = 64; /* 8 x 8 sprites of 8x16 cells */
+pub const OAM_VIEW_HEIGHT: usize = 128;
+pub const PALETTE_VIEW_WIDTH: usize = 16; /* one index per entry */
+pub const PALETTE_VIEW_HEIGHT: usize = 2; /* background, sprites */
+
+impl<'a> PPU<'a> {
+    /* the 2-bit pixels of a row of a tile */
+    fn peek_tile_row(&self, table: u16, tile: u8, row: u8) -> [u8; 8] {
+        let addr = table | ((tile as u16) << 4) | row as u16;
+        let low = self.mem.peek_mapper(addr);
+        let high = self.mem.peek_mapper(addr | 0x8);
+        let mut pixels = [0; 8];
+        for (i, p) in pixels.iter_mut().enumerate() {
+            *p = (((high >> (7 - i)) & 1) << 1) | ((low >> (7 - i)) & 1)
+        }
+        pixels
+    }
+
+    /* `palette` is 0..3 for the background and 4..7 for sprites */
+    fn peek_color(&self, palette: u8, pixel: u8) -> u16 {
+        let addr = if pixel == 0 {
+            0
+        } else {
+            ((palette as u16 & 7) << 2) | pixel as u16
+        };
+        (self.mem.read_palette(addr) & 0x3f) as u16
+    }
+
+    /* the two pattern tables drawn with `palette`; `out` must hold
+     * PATTERN_VIEW_WIDTH x PATTERN_VIEW_HEIGHT indices */
+    pub fn render_pattern_tables(&self, palette: u8, out: &mut [u16]) {
+        assert!(out.len() >= PATTERN_VIEW_WIDTH * PATTERN_VIEW_HEIGHT);
+        for table in 0..2 {
+            for tile in 0..=255u8 {
+                let x0 = table * 128 + (tile as usize & 0xf) * 8;
+                let y0 = (tile as usize >> 4) * 8;
+                for row in 0..8 {
+                    let pixels =
+                        self.peek_tile_row((table as u16) << 12, tile, row);
+                    let line = (y0 + row as usize) * PATTERN_VIEW_WIDTH + x0;
+                    for (i, &p) in pixels.iter().enumerate() {
+                        out[line + i] = self.peek_color(palette, p)
+                    }
+                }
+            }
+        }
+    }
+
+    /* the four nametables with the background pattern table selected by
+     * PPUCTRL; the borders of the scroll window given by t and fine x are
+     * drawn in `overlay`; `out` must hold NAMETABLE_VIEW_WIDTH x
+     * NAMETABLE_VIEW_HEIGHT indices */
+    pub fn render_nametables(&self, overlay: Option<u16>, out: &mut [u16]) {
+        assert!(out.len() >= NAMETABLE_VIEW_WIDTH * NAMETABLE_VIEW_HEIGHT);
+        let table = (self.ppuctl as u16 & 0x10) << 8;
+        for nt in 0..4u16 {
+            let base = nt << 10;
+            let x0 = (nt as usize & 1) * 256;
+            let y0 = (nt as usize >> 1) * 240;
+            for ty in 0..30u16 {
+                for tx in 0..32u16 {
+                    let tile = self.mem.peek_nametable(base | (ty << 5) | tx);
+                    let attr = self.mem.peek_nametable(
+                        base | 0x3c0 | ((ty >> 2) << 3) | (tx >> 2),
+                    );
+                    let palette = (attr >> (((ty & 2) << 1) | (tx & 2))) & 3;
+                    for row in 0..8 {
+                        let pixels = self.peek_tile_row(table, tile, row);
+                        let line = (y0 + ty as usize * 8 + row as usize) *
+                            NAMETABLE_VIEW_WIDTH +
+                            x0 +
+                            tx as usize * 8;
+                        for (i, &p) in pixels.iter().enumerate() {
+                            out[line + i] = self.peek_color(palette, p)
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(color) = overlay {
+            let nt = (self.t >> 10) as usize & 3;
+            let sx =
+                (nt & 1) * 256 + (self.t as usize & 0x1f) * 8 + self.x as usize;
+            let sy = (nt >> 1) * 240 +
+                ((self.t >> 5) as usize & 0x1f) * 8 +
+                ((self.t >> 12) as usize & 7);
+            let mut put = |x: usize, y: usize| {
+                out[(y % NAMETABLE_VIEW_HEIGHT) * NAMETABLE_VIEW_WIDTH +
+                    x % NAMETABLE_VIEW_WIDTH] = color
+            };
+            for i in 0..256 {
+                put(sx + i, sy);
+                put(sx + i, sy + 239);
+            }
+            for i in 0..240 {
+                put(sx, sy + i);
+                put(sx + 255, sy + i);
+            }
+        }
+    }
+
+    /* the 64 sprites in OAM order, with their flips and palettes applied;
+     * with 8x8 sprites the lower half of each cell is left as backdrop;
+     * `out` must hold OAM_VIEW_WIDTH x OAM_VIEW_HEIGHT indices */
+    pub fn render_oam(&self, out: &mut [u16]) {
+        assert!(out.len() >= OAM_VIEW_WIDTH * OAM_VIEW_HEIGHT);
+        let tall = self.get_spritesize() == 1;
+        let backdrop = self.peek_color(0, 0);
+        for (n, s) in self.oam.iter().enumerate() {
+            let (tile, attr) = (s.tile, s.attr);
+            let x0 = (n & 7) * 8;
+            let y0 = (n >> 3) * 16;
+            for row in 0..16u8 {
+                let line = (y0 + row as usize) * OAM_VIEW_WIDTH + x0;
+                if !tall && row >= 8 {
+                    for p in out[line..line + 8].iter_mut() {
+                        *p = backdrop
+                    }
+                    continue
+                }
+                let r = if attr & 0x80 == 0x80 {
+                    (if tall { 15 } else { 7 }) - row
+                } else {
+                    row
+                };
+                let (table, tidx) = if tall {
+                    ((tile as u16 & 1) << 12, (tile & !1u8) | (r >> 3))
+                } else {
+                    ((self.ppuctl as u16 & 0x08) << 9, tile)
+                };
+                let pixels = self.peek_tile_row(table, tidx, r & 7);
+                for i in 0..8 {
+                    let p = if attr & 0x40 == 0x40 {
+                        pixels[7 - i]
+                    } else {
+                        pixels[i]
+                    };
+                    out[line + i] = self.peek_color(4 | (attr & 3), p)
+                }
+            }
+        }
+    }
+
+    /* the palette RAM as seen through $3F00-$3F1F, mirrors included;
+     * `out` must hold PALETTE_VIEW_WIDTH x PALETTE_VIEW_HEIGHT indices */
+    pub fn render_palette(&self, out: &mut [u16]) {
+        assert!(out.len() >= PALETTE_VIEW_WIDTH * PALETTE_VIEW_HEIGHT);
+        for (i, p) in out[..32].iter_mut().enumerate() {
+            *p = (self.mem.read_palette(i as u16) & 0x3f) as u16
+        }
+    }
+}
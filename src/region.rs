@@ -0,0 +1,61 @@
+/* console timing variants */
+#[derive(Copy, Clone, PartialEq)]
+pub enum Region {
+    NTSC,
+    PAL,
+    Dendy, /* PAL frame timing with NTSC-like CPU/PPU ratio and APU */
+}
+
+impl Region {
+    pub fn cpu_freq(&self) -> u32 {
+        match self {
+            Region::NTSC => 1789773,
+            Region::PAL => 1662607,
+            Region::Dendy => 1773448,
+        }
+    }
+
+    /* PPU dots per CPU cycle, as (numerator, denominator) */
+    pub fn ppu_ratio(&self) -> (u8, u8) {
+        match self {
+            Region::PAL => (16, 5), /* 3.2 */
+            _ => (3, 1),
+        }
+    }
+
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::NTSC => 262,
+            _ => 312,
+        }
+    }
+
+    /* the scanline on which the vblank flag is set */
+    pub fn vblank_line(&self) -> u16 {
+        match self {
+            Region::Dendy => 291, /* 51 post-render lines */
+            _ => 241,
+        }
+    }
+
+    /* only the NTSC PPU skips a dot on odd frames */
+    pub fn odd_frame_skip(&self) -> bool {
+        *self == Region::NTSC
+    }
+
+    /* detect the region from an iNES header; only NES 2.0 headers tell it
+     * reliably, the iNES flag is rarely set */
+    pub fn from_ines_header(header: &[u8; 16]) -> Region {
+        if header[7] & 0x0c == 0x08 {
+            match header[12] & 3 {
+                1 => Region::PAL,
+                3 => Region::Dendy,
+                _ => Region::NTSC, /* 2: multi-region */
+            }
+        } else if header[9] & 1 == 1 {
+            Region::PAL
+        } else {
+            Region::NTSC
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::memory::CPUMemory;
+
+pub const RAM_SIZE: usize = 0x800;
+pub const SRAM_SIZE: usize = 0x2000;
+const SEARCH_SIZE: usize = RAM_SIZE + SRAM_SIZE;
+const SRAM_BASE: u16 = 0x6000;
+
+/* relation between the current value and the value in the last snapshot */
+#[derive(Copy, Clone)]
+pub enum Relation {
+    Unchanged,
+    Changed,
+    Greater,
+    Less,
+    IncreasedBy(u8),
+    DecreasedBy(u8),
+    Value(u8), /* compare to a specific value instead of the snapshot */
+}
+
+impl Relation {
+    fn test(&self, prev: u8, cur: u8) -> bool {
+        match *self {
+            Relation::Unchanged => cur == prev,
+            Relation::Changed => cur != prev,
+            Relation::Greater => cur > prev,
+            Relation::Less => cur < prev,
+            Relation::IncreasedBy(n) => cur == prev.wrapping_add(n),
+            Relation::DecreasedBy(n) => cur == prev.wrapping_sub(n),
+            Relation::Value(v) => cur == v,
+        }
+    }
+}
+
+/* a cheat search session over the CPU RAM ($0000-$07ff) followed by the
+ * cartridge PRG-RAM (reported as $6000-$7fff) */
+pub struct RamSearch {
+    prev: [u8; SEARCH_SIZE],
+    cand: [u8; SEARCH_SIZE / 8], /* bitmap of the remaining candidates */
+    size: usize,
+    count: usize,
+}
+
+fn index_to_addr(idx: usize) -> u16 {
+    if idx < RAM_SIZE {
+        idx as u16
+    } else {
+        SRAM_BASE + (idx - RAM_SIZE) as u16
+    }
+}
+
+impl RamSearch {
+    pub fn new() -> Self {
+        RamSearch {
+            prev: [0; SEARCH_SIZE],
+            cand: [0; SEARCH_SIZE / 8],
+            size: 0,
+            count: 0,
+        }
+    }
+
+    fn snapshot(&mut self, mem: &CPUMemory) {
+        for i in 0..RAM_SIZE {
+            self.prev[i] = mem.peek(i as u16)
+        }
+        let sram = mem.get_cart_sram();
+        let len = core::cmp::min(sram.len(), SRAM_SIZE);
+        self.prev[RAM_SIZE..RAM_SIZE + len].copy_from_slice(&sram[..len]);
+        self.size = RAM_SIZE + len;
+    }
+
+    #[inline(always)]
+    fn current(&self, mem: &CPUMemory, idx: usize) -> u8 {
+        if idx < RAM_SIZE {
+            mem.peek(idx as u16)
+        } else {
+            mem.get_cart_sram()[idx - RAM_SIZE]
+        }
+    }
+
+    #[inline(always)]
+    fn is_candidate(&self, idx: usize) -> bool {
+        (self.cand[idx >> 3] >> (idx & 7)) & 1 == 1
+    }
+
+    /* start over: every byte becomes a candidate */
+    pub fn reset(&mut self, mem: &CPUMemory) {
+        self.snapshot(mem);
+        for b in self.cand.iter_mut() {
+            *b = 0
+        }
+        for i in 0..self.size {
+            self.cand[i >> 3] |= 1 << (i & 7)
+        }
+        self.count = self.size;
+    }
+
+    /* keep the candidates satisfying the relation, then take a new snapshot
+     * to compare against next time; returns the number of candidates left */
+    pub fn filter(&mut self, mem: &CPUMemory, rel: Relation) -> usize {
+        let mut count = 0;
+        for i in 0..self.size {
+            if self.is_candidate(i) {
+                if rel.test(self.prev[i], self.current(mem, i)) {
+                    count += 1
+                } else {
+                    self.cand[i >> 3] &= !(1 << (i & 7))
+                }
+            }
+        }
+        self.count = count;
+        self.snapshot(mem);
+        count
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.count
+    }
+
+    /* iterate over the remaining candidates as (addr, value in snapshot) */
+    pub fn candidates(&self) -> Candidates<'_> {
+        Candidates {
+            search: self,
+            idx: 0,
+        }
+    }
+}
+
+impl Default for RamSearch {
+    fn default() -> Self {
+        RamSearch::new()
+    }
+}
+
+pub struct Candidates<'a> {
+    search: &'a RamSearch,
+    idx: usize,
+}
+
+impl<'a> Iterator for Candidates<'a> {
+    type Item = (u16, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.idx < self.search.size {
+            let i = self.idx;
+            self.idx += 1;
+            if self.search.is_candidate(i) {
+                return Some((index_to_addr(i), self.search.prev[i]))
+            }
+        }
+        None
+    }
+}
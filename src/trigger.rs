@@ -0,0 +1,396 @@
+use crate::memory::CPUMemory;
+
+/* A small condition engine in the spirit of RetroAchievements:
+ *
+ *   trigger := group ('S' group)*      (core group followed by alternatives)
+ *   group   := cond ('_' cond)*
+ *   cond    := [('R' | 'P') ':'] operand op operand ['.' hits '.']
+ *   operand := ['d'] memref | decimal | 'h' hex
+ *   memref  := '0x' [size] hex          (size: H = 8-bit, none = 16-bit,
+ *                                         W = 24-bit, X = 32-bit,
+ *                                         M..T = bit 0..7,
+ *                                         L/U = lower/upper nibble)
+ *   op      := '=' | '==' | '!=' | '<' | '<=' | '>' | '>='
+ *
+ * 'd' reads the value from the previous frame, "R:" resets all hit counts
+ * when true and "P:" pauses the group it belongs to. The trigger is met when
+ * the core group and at least one alternative group (if any) are true. */
+
+pub const MAX_CONDS: usize = 32;
+pub const MAX_GROUPS: usize = 8;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Size {
+    Bit(u8),
+    Low,
+    High,
+    Byte,
+    Word,
+    Tbyte,
+    Dword,
+}
+
+#[derive(Copy, Clone)]
+enum Operand {
+    Const(u32),
+    Mem {
+        size: Size,
+        addr: u16,
+        delta: bool,
+        prev: u32,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Flag {
+    None,
+    Reset,
+    Pause,
+}
+
+#[derive(Copy, Clone)]
+struct Cond {
+    flag: Flag,
+    lhs: Operand,
+    op: Op,
+    rhs: Operand,
+    target: u32,
+    hits: u32,
+    group: u8,
+}
+
+fn read_size(mem: &CPUMemory, size: Size, addr: u16) -> u32 {
+    let byte = |i: u16| mem.peek(addr.wrapping_add(i)) as u32;
+    match size {
+        Size::Bit(n) => (byte(0) >> n) & 1,
+        Size::Low => byte(0) & 0xf,
+        Size::High => byte(0) >> 4,
+        Size::Byte => byte(0),
+        Size::Word => byte(0) | (byte(1) << 8),
+        Size::Tbyte => byte(0) | (byte(1) << 8) | (byte(2) << 16),
+        Size::Dword => {
+            byte(0) | (byte(1) << 8) | (byte(2) << 16) | (byte(3) << 24)
+        }
+    }
+}
+
+impl Operand {
+    fn value(&self, mem: &CPUMemory) -> u32 {
+        match *self {
+            Operand::Const(v) => v,
+            Operand::Mem {
+                size,
+                addr,
+                delta,
+                prev,
+            } => {
+                if delta {
+                    prev
+                } else {
+                    read_size(mem, size, addr)
+                }
+            }
+        }
+    }
+
+    /* remember the current value for the delta reads of the next frame */
+    fn update(&mut self, mem: &CPUMemory) {
+        if let Operand::Mem {
+            size,
+            addr,
+            ref mut prev,
+            ..
+        } = *self
+        {
+            *prev = read_size(mem, size, addr)
+        }
+    }
+}
+
+impl Cond {
+    fn test(&self, mem: &CPUMemory) -> bool {
+        let a = self.lhs.value(mem);
+        let b = self.rhs.value(mem);
+        match self.op {
+            Op::Eq => a == b,
+            Op::Ne => a != b,
+            Op::Lt => a < b,
+            Op::Le => a <= b,
+            Op::Gt => a > b,
+            Op::Ge => a >= b,
+        }
+    }
+
+    /* update the hit count and tell if the condition is met */
+    fn eval(&mut self, mem: &CPUMemory) -> bool {
+        let res = self.test(mem);
+        if self.target == 0 {
+            return res
+        }
+        if res && self.hits < self.target {
+            self.hits += 1
+        }
+        self.hits >= self.target
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self, radix: u32) -> Option<u32> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if (c as char).is_digit(radix) {
+                self.pos += 1
+            } else {
+                break
+            }
+        }
+        let digits = core::str::from_utf8(&self.s[start..self.pos]).ok()?;
+        u32::from_str_radix(digits, radix).ok()
+    }
+
+    fn operand(&mut self) -> Option<Operand> {
+        let delta = self.eat(b'd');
+        if self.s[self.pos..].starts_with(b"0x") {
+            self.pos += 2;
+            let size = match self.peek()? {
+                b'H' => Size::Byte,
+                b'W' => Size::Tbyte,
+                b'X' => Size::Dword,
+                b'L' => Size::Low,
+                b'U' => Size::High,
+                c @ b'M'..=b'T' => Size::Bit(c - b'M'),
+                b' ' => Size::Word,
+                _ => {
+                    /* no size prefix: 16-bit */
+                    self.pos -= 1;
+                    Size::Word
+                }
+            };
+            self.pos += 1;
+            let addr = self.number(16)?;
+            if addr > 0xffff {
+                return None
+            }
+            Some(Operand::Mem {
+                size,
+                addr: addr as u16,
+                delta,
+                prev: 0,
+            })
+        } else if delta {
+            None
+        } else if self.eat(b'h') {
+            Some(Operand::Const(self.number(16)?))
+        } else {
+            Some(Operand::Const(self.number(10)?))
+        }
+    }
+
+    fn op(&mut self) -> Option<Op> {
+        Some(match self.peek()? {
+            b'=' => {
+                self.pos += 1;
+                self.eat(b'=');
+                Op::Eq
+            }
+            b'!' => {
+                self.pos += 1;
+                if !self.eat(b'=') {
+                    return None
+                }
+                Op::Ne
+            }
+            b'<' => {
+                self.pos += 1;
+                if self.eat(b'=') {
+                    Op::Le
+                } else {
+                    Op::Lt
+                }
+            }
+            b'>' => {
+                self.pos += 1;
+                if self.eat(b'=') {
+                    Op::Ge
+                } else {
+                    Op::Gt
+                }
+            }
+            _ => return None,
+        })
+    }
+
+    fn cond(&mut self, group: u8) -> Option<Cond> {
+        let mut flag = Flag::None;
+        if self.s.get(self.pos + 1) == Some(&b':') {
+            flag = match self.peek()? {
+                b'R' => Flag::Reset,
+                b'P' => Flag::Pause,
+                _ => return None, /* unsupported flag */
+            };
+            self.pos += 2;
+        }
+        let lhs = self.operand()?;
+        let op = self.op()?;
+        let rhs = self.operand()?;
+        let mut target = 0;
+        if self.eat(b'.') {
+            target = self.number(10)?;
+            if !self.eat(b'.') {
+                return None
+            }
+        } else if self.eat(b'(') {
+            target = self.number(10)?;
+            if !self.eat(b')') {
+                return None
+            }
+        }
+        Some(Cond {
+            flag,
+            lhs,
+            op,
+            rhs,
+            target,
+            hits: 0,
+            group,
+        })
+    }
+}
+
+pub struct Trigger {
+    conds: [Cond; MAX_CONDS],
+    len: usize,
+    ngroups: usize,
+    primed: bool,
+}
+
+impl Trigger {
+    pub fn parse(s: &str) -> Option<Self> {
+        let dummy = Cond {
+            flag: Flag::None,
+            lhs: Operand::Const(0),
+            op: Op::Eq,
+            rhs: Operand::Const(0),
+            target: 0,
+            hits: 0,
+            group: 0,
+        };
+        let mut t = Trigger {
+            conds: [dummy; MAX_CONDS],
+            len: 0,
+            ngroups: 1,
+            primed: false,
+        };
+        let mut p = Parser {
+            s: s.trim().as_bytes(),
+            pos: 0,
+        };
+        loop {
+            if t.len == MAX_CONDS {
+                return None
+            }
+            t.conds[t.len] = p.cond(t.ngroups as u8 - 1)?;
+            t.len += 1;
+            if p.eat(b'S') {
+                if t.ngroups == MAX_GROUPS {
+                    return None
+                }
+                t.ngroups += 1
+            } else if !p.eat(b'_') {
+                break
+            }
+        }
+        if p.pos == p.s.len() {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    /* clear all hit counts */
+    pub fn reset(&mut self) {
+        for c in self.conds[..self.len].iter_mut() {
+            c.hits = 0
+        }
+    }
+
+    /* evaluate once per frame, returns true if the trigger is met */
+    pub fn eval(&mut self, mem: &CPUMemory) -> bool {
+        let conds = &mut self.conds[..self.len];
+        if !self.primed {
+            /* deltas have nothing to compare against on the first frame */
+            for c in conds.iter_mut() {
+                c.lhs.update(mem);
+                c.rhs.update(mem);
+            }
+            self.primed = true
+        }
+        let mut group_ok = [true; MAX_GROUPS];
+        let mut paused = [false; MAX_GROUPS];
+        let mut reset = false;
+        for c in conds.iter_mut() {
+            if c.flag == Flag::Pause && c.eval(mem) {
+                paused[c.group as usize] = true
+            }
+        }
+        for c in conds.iter_mut() {
+            let g = c.group as usize;
+            if paused[g] {
+                group_ok[g] = false;
+                continue
+            }
+            match c.flag {
+                Flag::Pause => (),
+                Flag::Reset => {
+                    if c.test(mem) {
+                        reset = true
+                    }
+                }
+                Flag::None => {
+                    if !c.eval(mem) {
+                        group_ok[g] = false
+                    }
+                }
+            }
+        }
+        for c in conds.iter_mut() {
+            c.lhs.update(mem);
+            c.rhs.update(mem);
+        }
+        if reset {
+            self.reset();
+            return false
+        }
+        group_ok[0] &&
+            (self.ngroups == 1 ||
+                group_ok[1..self.ngroups].iter().any(|&ok| ok))
+    }
+}
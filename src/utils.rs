@@ -54,6 +54,72 @@ impl Sampler {
     }
 }
 
+#[derive(Copy, Clone)]
+pub enum RamPattern {
+    Zeros,       /* all 0x00 */
+    Ones,        /* all 0xff */
+    Fceux,       /* four 0x00 followed by four 0xff, as FCEUX does */
+    Random(u32), /* pseudo-random bytes from the given seed */
+}
+
+/* power-on contents of the RAMs that are not cleared by hardware */
+pub struct RamInit {
+    pattern: RamPattern,
+    rng: u32,
+}
+
+impl RamInit {
+    pub fn new(pattern: RamPattern) -> Self {
+        let rng = match pattern {
+            RamPattern::Random(seed) => seed ^ 0x9e3779b9,
+            _ => 0,
+        };
+        RamInit {
+            pattern,
+            rng: if rng == 0 { 1 } else { rng },
+        }
+    }
+
+    pub fn get_pattern(&self) -> RamPattern {
+        self.pattern
+    }
+
+    /* xorshift32, so that each filled region gets different bytes */
+    pub fn rand(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        match self.pattern {
+            RamPattern::Zeros => {
+                for b in buf.iter_mut() {
+                    *b = 0x00
+                }
+            }
+            RamPattern::Ones => {
+                for b in buf.iter_mut() {
+                    *b = 0xff
+                }
+            }
+            RamPattern::Fceux => {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = if i & 4 == 0 { 0x00 } else { 0xff }
+                }
+            }
+            RamPattern::Random(_) => {
+                for b in buf.iter_mut() {
+                    *b = (self.rand() >> 24) as u8
+                }
+            }
+        }
+    }
+}
+
 pub trait Read {
     fn read(&mut self, buf: &mut [u8]) -> Option<usize>;
 }
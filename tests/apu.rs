@@ -0,0 +1,43 @@
+mod common;
+
+use common::{idle_cart, run_cart};
+
+/* the CPU cycle (counted from the $4017 write, which happens on an odd or
+ * even cycle since power-up) on which the frame IRQ flag is seen, after
+ * reading $4015 on cycle `read_at` if any */
+fn frame_irq_cycle(odd: bool, read_at: Option<u32>) -> Option<u32> {
+    let mut res = None;
+    run_cart(idle_cart(), 0, |cpu, _| {
+        if (cpu.get_state().cycles & 1 == 1) != odd {
+            cpu.mem.bus.stall()
+        }
+        cpu.mem.write_without_tick(0x4017, 0x00); /* 4-step, IRQ on */
+        let bus = &cpu.mem.bus;
+        let apu = bus.get_apu();
+        for i in 0..40000 {
+            bus.stall();
+            if Some(i) == read_at {
+                assert_ne!(cpu.mem.read_without_tick(0x4015) & 0x40, 0)
+            } else if Some(i) > read_at && apu.get_state().frame_irq {
+                res = Some(i);
+                break
+            }
+        }
+    });
+    res
+}
+
+#[test]
+fn frame_irq_race() {
+    /* the sequencer is reset 3 cycles after a write on an APU cycle (even
+     * here) and 4 cycles after a write between two */
+    assert_eq!(frame_irq_cycle(false, None), Some(29830));
+    assert_eq!(frame_irq_cycle(true, None), Some(29831));
+    for &(odd, first) in [(false, 29830), (true, 29831)].iter() {
+        /* the flag is raised again after a read on either of the first two
+         * IRQ cycles, but not after a read on the last one */
+        assert_eq!(frame_irq_cycle(odd, Some(first)), Some(first + 1));
+        assert_eq!(frame_irq_cycle(odd, Some(first + 1)), Some(first + 2));
+        assert_eq!(frame_irq_cycle(odd, Some(first + 2)), None)
+    }
+}
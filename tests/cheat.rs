@@ -0,0 +1,158 @@
+mod common;
+
+use common::{idle_cart, run_cart, run_frames, Buf};
+use runes::cheat::{Cheat, CheatEngine};
+
+fn saved(engine: &CheatEngine) -> Vec<u8> {
+    let mut buf = Buf {
+        data: Vec::new(),
+        pos: 0,
+    };
+    assert!(engine.save(&mut buf));
+    buf.data
+}
+
+fn load(data: Vec<u8>) -> Option<CheatEngine> {
+    let mut engine = CheatEngine::new();
+    if engine.load(&mut Buf { data, pos: 0 }) {
+        Some(engine)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn save_and_load() {
+    let mut engine = CheatEngine::new();
+    engine.add(Cheat::new(0x0123, 0x45, None)).unwrap();
+    engine.add(Cheat::new(0x8abc, 0x67, Some(0x89))).unwrap();
+    engine.set_enabled(0, false);
+    engine.set_active(false);
+
+    let loaded = load(saved(&engine)).unwrap();
+    assert!(!loaded.is_active());
+    assert_eq!(loaded.len(), 2);
+    let c = loaded.get(0).unwrap();
+    assert_eq!(
+        (c.addr, c.value, c.compare, c.enabled),
+        (0x0123, 0x45, None, false)
+    );
+    let c = loaded.get(1).unwrap();
+    assert_eq!(
+        (c.addr, c.value, c.compare, c.enabled),
+        (0x8abc, 0x67, Some(0x89), true)
+    );
+}
+
+#[test]
+fn reject_invalid_data() {
+    let mut engine = CheatEngine::new();
+    engine.add(Cheat::new(0x0123, 0x45, Some(0x10))).unwrap();
+    let data = saved(&engine);
+
+    let mut bad_active = data.clone();
+    bad_active[0] = 2;
+    assert!(load(bad_active).is_none());
+
+    let mut too_many = data.clone();
+    too_many[1] = 0xff;
+    assert!(load(too_many).is_none());
+
+    let mut bad_flags = data.clone();
+    *bad_flags.last_mut().unwrap() |= 0x80;
+    assert!(load(bad_flags).is_none());
+
+    let mut truncated = data;
+    truncated.pop();
+    assert!(load(truncated).is_none());
+
+    /* a failed load leaves the engine untouched */
+    let mut engine = CheatEngine::new();
+    engine.add_code("0123:45").unwrap();
+    assert!(!engine.load(&mut Buf {
+        data: vec![1, 1, 0, 0],
+        pos: 0
+    }));
+    assert_eq!(engine.len(), 1);
+}
+
+fn fields(c: Cheat) -> (u16, u8, Option<u8>) {
+    (c.addr, c.value, c.compare)
+}
+
+#[test]
+fn game_genie_codes() {
+    let gg = |code| Cheat::from_game_genie(code).map(fields);
+    assert_eq!(gg("SXIOPO"), Some((0x91d9, 0xad, None)));
+    assert_eq!(gg("GOSSIP"), Some((0xd1dd, 0x14, None)));
+    assert_eq!(gg("gossip"), Some((0xd1dd, 0x14, None)));
+    assert_eq!(gg("GOSSIPNK"), Some((0xd1dd, 0x1c, Some(0xc7))));
+    assert_eq!(gg("YEUZUGAA"), Some((0xacb3, 0x07, Some(0x00))));
+    assert!(gg("GOSSI").is_none());
+    assert!(gg("GOSSIPN").is_none());
+    assert!(gg("GOSSIB").is_none());
+    /* a code without a colon is taken as Game Genie */
+    assert_eq!(Cheat::parse(" SXIOPO ").map(fields), gg("SXIOPO"));
+}
+
+#[test]
+fn raw_codes() {
+    let raw = |code| Cheat::from_raw(code).map(fields);
+    assert_eq!(raw("0075:09"), Some((0x0075, 0x09, None)));
+    assert_eq!(raw("c0de?a5:5a"), Some((0xc0de, 0x5a, Some(0xa5))));
+    assert!(raw("10000:00").is_none());
+    assert!(raw("0075:100").is_none());
+    assert!(raw("0075?:09").is_none());
+    assert!(raw("0075").is_none());
+    assert!(raw("xyz:09").is_none());
+}
+
+#[test]
+fn substitute_reads() {
+    let mut engine = CheatEngine::new();
+    engine.add_code("8000?4c:ea").unwrap();
+    engine.add_code("8001:12").unwrap();
+    /* RAM codes freeze instead of substituting */
+    engine.add_code("0010:34").unwrap();
+    assert_eq!(engine.read(0x8000, 0x4c), 0xea);
+    assert_eq!(engine.read(0x8000, 0x4d), 0x4d);
+    assert_eq!(engine.read(0x8001, 0x00), 0x12);
+    assert_eq!(engine.read(0x8002, 0x00), 0x00);
+    assert_eq!(engine.read(0x0010, 0x00), 0x00);
+    engine.set_enabled(1, false);
+    assert_eq!(engine.read(0x8001, 0x00), 0x00);
+    engine.set_active(false);
+    assert_eq!(engine.read(0x8000, 0x4c), 0x4c);
+
+    /* on the CPU bus */
+    run_cart(idle_cart(), 0, |cpu, _| {
+        cpu.mem.cheats.add_code("8000?4c:ea").unwrap();
+        cpu.mem.cheats.add_code("8001?ff:ea").unwrap();
+        assert_eq!(cpu.mem.read_without_tick(0x8000), 0xea);
+        assert_eq!(cpu.mem.read_without_tick(0x8001), 0x00);
+    })
+}
+
+#[test]
+fn freeze_ram() {
+    run_cart(idle_cart(), 0, |cpu, _| {
+        cpu.mem.cheats.add_code("0010:34").unwrap();
+        cpu.mem.cheats.add_code("0011?05:56").unwrap();
+        cpu.mem.cheats.add_code("0012?05:78").unwrap();
+        cpu.mem.cheats.add_code("6000:9a").unwrap();
+        cpu.mem.write_without_tick(0x0011, 0x05);
+        cpu.mem.write_without_tick(0x0012, 0x06);
+        run_frames(cpu, 1);
+        let ram = [0x0010, 0x0011, 0x0012, 0x6000].map(|a| cpu.mem.peek(a));
+        assert_eq!(ram, [0x34, 0x56, 0x06, 0x9a]);
+        /* written back at the end of every frame */
+        cpu.mem.write_without_tick(0x0010, 0x00);
+        assert_eq!(cpu.mem.peek(0x0010), 0x00);
+        run_frames(cpu, 1);
+        assert_eq!(cpu.mem.peek(0x0010), 0x34);
+        cpu.mem.cheats.set_active(false);
+        cpu.mem.write_without_tick(0x0010, 0x00);
+        run_frames(cpu, 1);
+        assert_eq!(cpu.mem.peek(0x0010), 0x00);
+    })
+}
@@ -0,0 +1,270 @@
+/* a headless machine for the integration tests: an iNES image (or a
+ * hand-made cartridge) running with a screen that keeps the last frame */
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use runes::apu::{Speaker, APU};
+use runes::cartridge::{BankType, Cartridge, MirrorType};
+use runes::controller::Controller;
+use runes::event::{Event, EventRecorder, MAX_EVENTS};
+use runes::mapper::{Mapper, Mapper1, Mapper2, Mapper4, RefMapper};
+use runes::memory::{CPUMemory, PPUMemory};
+use runes::mos6502::CPU;
+use runes::ppu::{Screen, PPU};
+use runes::utils::{RamInit, RamPattern, Read, Write};
+
+pub struct Cart {
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+    pub sram: Vec<u8>,
+    pub nt_ram: Vec<u8>,
+    pub mirror: MirrorType,
+}
+
+impl Cart {
+    pub fn from_ines(rom: &[u8]) -> (Self, u8) {
+        let prg_len = rom[4] as usize * 0x4000;
+        let chr_len = rom[5] as usize * 0x2000;
+        let mirror = if rom[6] & 8 != 0 {
+            MirrorType::Four
+        } else if rom[6] & 1 != 0 {
+            MirrorType::Vertical
+        } else {
+            MirrorType::Horizontal
+        };
+        let chr = if chr_len == 0 {
+            vec![0; 0x2000] /* CHR-RAM */
+        } else {
+            rom[16 + prg_len..16 + prg_len + chr_len].to_vec()
+        };
+        let cart = Cart {
+            prg: rom[16..16 + prg_len].to_vec(),
+            chr,
+            sram: vec![0; 0x2000],
+            nt_ram: match mirror {
+                MirrorType::Four => vec![0; 0x1000],
+                _ => Vec::new(),
+            },
+            mirror,
+        };
+        (cart, (rom[7] & 0xf0) | (rom[6] >> 4))
+    }
+
+    fn bank(&self, kind: BankType) -> &Vec<u8> {
+        match kind {
+            BankType::PrgRom => &self.prg,
+            BankType::ChrRom => &self.chr,
+            BankType::Sram => &self.sram,
+            BankType::NtRam => &self.nt_ram,
+        }
+    }
+}
+
+impl Cartridge for Cart {
+    fn get_size(&self, kind: BankType) -> usize {
+        self.bank(kind).len()
+    }
+
+    fn get_bank<'a>(
+        &self,
+        base: usize,
+        size: usize,
+        kind: BankType,
+    ) -> &'a [u8] {
+        let bank = &self.bank(kind)[base..base + size];
+        unsafe { &*(bank as *const [u8]) }
+    }
+
+    fn get_bank_mut<'a>(
+        &mut self,
+        base: usize,
+        size: usize,
+        kind: BankType,
+    ) -> &'a mut [u8] {
+        let bank = match kind {
+            BankType::PrgRom => &mut self.prg,
+            BankType::ChrRom => &mut self.chr,
+            BankType::Sram => &mut self.sram,
+            BankType::NtRam => &mut self.nt_ram,
+        };
+        unsafe { &mut *(&mut bank[base..base + size] as *mut [u8]) }
+    }
+
+    fn get_mirror_type(&self) -> MirrorType {
+        self.mirror
+    }
+
+    fn set_mirror_type(&mut self, mt: MirrorType) {
+        self.mirror = mt
+    }
+
+    fn load(&mut self, _reader: &mut dyn Read) -> bool {
+        true
+    }
+
+    fn save(&self, _writer: &mut dyn Write) -> bool {
+        true
+    }
+
+    fn load_sram(&mut self, _reader: &mut dyn Read) -> bool {
+        true
+    }
+
+    fn save_sram(&self, _writer: &mut dyn Write) -> bool {
+        true
+    }
+}
+
+/* an NROM cartridge whose program spins forever, with a solid tile 1 */
+pub fn idle_cart() -> Cart {
+    let mut prg = vec![0; 0x8000];
+    prg[..3].copy_from_slice(&[0x4c, 0x00, 0x80]); /* jmp $8000 */
+    prg[3] = 0x40; /* rti */
+    /* nmi $8003, reset $8000, irq $8003 */
+    prg[0x7ffa..].copy_from_slice(&[0x03, 0x80, 0x00, 0x80, 0x03, 0x80]);
+    let mut chr = vec![0; 0x2000];
+    for b in chr[0x10..0x18].iter_mut() {
+        *b = 0xff
+    }
+    Cart {
+        prg,
+        chr,
+        sram: vec![0; 0x2000],
+        nt_ram: Vec::new(),
+        mirror: MirrorType::Horizontal,
+    }
+}
+
+/* the last complete frame, as 256x240 color indices */
+#[derive(Clone)]
+pub struct Frame(Rc<RefCell<Vec<u16>>>);
+
+impl Frame {
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.0.borrow()[y * 256 + x]
+    }
+}
+
+struct TestScreen {
+    cur: Vec<u16>,
+    last: Frame,
+}
+
+impl Screen for TestScreen {
+    fn put(&mut self, x: u8, y: u8, color: u16) {
+        self.cur[y as usize * 256 + x as usize] = color
+    }
+
+    fn render(&mut self) {
+        self.last.0.borrow_mut().copy_from_slice(&self.cur)
+    }
+
+    fn frame(&mut self) {}
+}
+
+/* an in-memory state file */
+#[derive(Default)]
+pub struct Buf {
+    pub data: Vec<u8>,
+    pub pos: usize,
+}
+
+impl Read for Buf {
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Some(n)
+    }
+}
+
+impl Write for Buf {
+    fn write(&mut self, buf: &[u8]) -> Option<usize> {
+        self.data.extend_from_slice(buf);
+        Some(buf.len())
+    }
+}
+
+struct Mute;
+
+impl Speaker for Mute {
+    fn queue(&mut self, _sample: i16) {}
+}
+
+/* power up a machine with the cartridge and zeroed RAM and hand it to `f`;
+ * an event recorder is attached, disabled */
+pub fn run_cart<F>(cart: Cart, mapper_id: u8, f: F)
+where
+    F: FnOnce(&mut CPU, &Frame),
+{
+    run_cart_with(cart, mapper_id, RamPattern::Zeros, None, f)
+}
+
+pub fn run_cart_with<F>(
+    cart: Cart,
+    mapper_id: u8,
+    pattern: RamPattern,
+    ctl1: Option<&dyn Controller>,
+    f: F,
+) where
+    F: FnOnce(&mut CPU, &Frame),
+{
+    let mut m: Box<dyn Mapper> = match mapper_id {
+        0 | 2 => Box::new(Mapper2::new(cart)),
+        1 => Box::new(Mapper1::new(cart)),
+        4 => Box::new(Mapper4::new(cart)),
+        id => panic!("unsupported mapper {}", id),
+    };
+    let mapper = RefMapper::new(&mut *m);
+    let frame = Frame(Rc::new(RefCell::new(vec![0; 256 * 240])));
+    let mut scr = TestScreen {
+        cur: vec![0; 256 * 240],
+        last: frame.clone(),
+    };
+    let mut spkr = Mute;
+    let mut storage = vec![Event::default(); 2 * MAX_EVENTS];
+    let events = EventRecorder::new(&mut storage);
+    events.set_enabled(false);
+    let mut cpu = CPU::new(CPUMemory::new(&mapper, ctl1, None));
+    cpu.mem.set_event_recorder(Some(&events));
+    let mut ppu = PPU::new(PPUMemory::new(&mapper), &mut scr);
+    let mut apu = APU::new(&mut spkr);
+    let cpu_ptr = &mut cpu as *mut CPU;
+    cpu.mem.bus.attach(cpu_ptr, &mut ppu, &mut apu);
+    let mut init = RamInit::new(pattern);
+    cpu.mem.powerup(&mut init);
+    ppu.powerup(&mut init);
+    cpu.powerup();
+    f(&mut cpu, &frame)
+}
+
+pub fn run_rom<F>(path: &str, f: F)
+where
+    F: FnOnce(&mut CPU, &Frame),
+{
+    let rom = std::fs::read(
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(path),
+    )
+    .unwrap();
+    let (cart, mapper_id) = Cart::from_ines(&rom);
+    run_cart(cart, mapper_id, f)
+}
+
+pub fn run_frames(cpu: &mut CPU, n: usize) {
+    for _ in 0..n {
+        cpu.run_frame()
+    }
+}
+
+/* the blargg ROMs of 2005 leave their result code in $f8: 1 means
+ * passed, anything else is the number of the failed test */
+pub fn blargg_result(path: &str, frames: usize) -> u8 {
+    let mut res = 0;
+    run_rom(path, |cpu, _| {
+        run_frames(cpu, frames);
+        res = cpu.mem.peek(0xf8)
+    });
+    res
+}
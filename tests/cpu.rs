@@ -0,0 +1,22 @@
+mod common;
+
+use common::{idle_cart, run_cart};
+
+#[test]
+fn irq_keeps_a_pending_nmi() {
+    let mut cart = idle_cart();
+    cart.prg[..4].copy_from_slice(&[0x58, 0x4c, 0x01, 0x80]); /* cli; jmp $8001 */
+    run_cart(cart, 0, |cpu, _| {
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.get_int(), 0);
+        cpu.trigger_delayed_nmi();
+        cpu.trigger_irq();
+        let st = cpu.get_state();
+        assert!(st.nmi_pending && !st.irq_pending);
+        cpu.trigger_nmi();
+        cpu.trigger_irq();
+        let st = cpu.get_state();
+        assert!(st.nmi_pending && !st.irq_pending)
+    })
+}
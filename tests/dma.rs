@@ -0,0 +1,60 @@
+mod common;
+
+use std::cell::Cell;
+
+use common::{idle_cart, run_cart_with};
+use runes::controller::Controller;
+use runes::memory::VMem;
+use runes::utils::{RamPattern, Read, Write};
+
+/* a controller that counts how many times its shift register is clocked */
+#[derive(Default)]
+struct Clocks(Cell<u32>);
+
+impl Controller for Clocks {
+    fn read(&self) -> u8 {
+        self.0.set(self.0.get() + 1);
+        0
+    }
+
+    fn write(&self, _data: u8) {}
+
+    fn load(&mut self, _reader: &mut dyn Read) -> bool {
+        true
+    }
+
+    fn save(&self, _writer: &mut dyn Write) -> bool {
+        true
+    }
+}
+
+/* the clocks seen by one $4016 read that a DMC fetch steals from, after
+ * `delay` cycles on top of power-up */
+fn joypad_clocks_with_dmc_steal(delay: usize) -> u32 {
+    let mut cart = idle_cart();
+    cart.prg[0] = 0xea; /* nop */
+    let ctl = Clocks::default();
+    run_cart_with(cart, 0, RamPattern::Zeros, Some(&ctl), |cpu, _| {
+        /* the read takes one of the cycles the nop leaves */
+        cpu.step();
+        assert!(cpu.get_state().cycle > 0);
+        cpu.mem.write_without_tick(0x4010, 0x0f);
+        cpu.mem.write_without_tick(0x4013, 0x01);
+        /* the sample buffer is empty, the fetch is due right away */
+        cpu.mem.write_without_tick(0x4015, 0x10);
+        for _ in 0..delay {
+            cpu.mem.bus.stall()
+        }
+        cpu.mem.read(0x4016);
+        assert!(!cpu.mem.bus.get_apu().dmc.dma_pending())
+    });
+    ctl.0.get()
+}
+
+#[test]
+fn dmc_dma_during_joypad_read() {
+    /* the halted reads count as one, the read after the fetch as another,
+     * with or without the alignment cycle */
+    assert_eq!(joypad_clocks_with_dmc_steal(0), 2);
+    assert_eq!(joypad_clocks_with_dmc_steal(1), 2);
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use common::{idle_cart, run_cart, run_frames};
+use runes::event::{Event, EventKind, EventRecorder};
+
+#[test]
+fn register_writes_are_logged_per_frame() {
+    run_cart(idle_cart(), 0, |cpu, _| {
+        run_frames(cpu, 3);
+        let events = cpu.mem.get_event_recorder().unwrap();
+        events.set_enabled(true);
+        cpu.mem.write_without_tick(0x2000, 0x80);
+        assert_eq!(events.get_current().events().len(), 1);
+        run_frames(cpu, 1);
+        {
+            let log = events.get_last();
+            let ev = log.events()[0];
+            assert!(ev.kind == EventKind::Write);
+            assert_eq!((ev.addr, ev.data), (0x2000, 0x80));
+            assert_eq!(ev.scanline, 241);
+            /* the NMI it enabled is in the next frame */
+            assert_eq!(log.get_frame() + 1, cpu.mem.bus.get_ppu().frame);
+        }
+        run_frames(cpu, 1);
+        let log = events.get_last();
+        assert!(log.events().iter().any(|e| e.kind == EventKind::Nmi));
+        assert!(!log.events().iter().any(|e| e.kind == EventKind::Write));
+    })
+}
+
+#[test]
+fn full_log_counts_dropped_events() {
+    let mut storage = [Event::default(); 4];
+    let events = EventRecorder::new(&mut storage);
+    for addr in 0..5 {
+        events.record(Event {
+            addr,
+            ..Event::default()
+        })
+    }
+    assert_eq!(events.get_current().events().len(), 2);
+    assert_eq!(events.get_current().get_dropped(), 3);
+    events.end_frame(1);
+    assert_eq!(events.get_last().events()[1].addr, 1);
+    assert_eq!(events.get_current().events().len(), 0);
+    assert_eq!(events.get_current().get_frame(), 1);
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use common::{run_cart, run_frames, Cart};
+use runes::cartridge::MirrorType;
+use runes::mos6502::CPU;
+
+/* an MMC3 board whose fixed last bank enables interrupts and spins, the
+ * IRQ handler counts in $10 and acknowledges through $e000 */
+fn mmc3_cart() -> Cart {
+    let mut prg = vec![0; 0x8000];
+    #[rustfmt::skip]
+    let code = [
+        0x58,             /* $e000: cli */
+        0x4c, 0x01, 0xe0, /* jmp $e001 */
+        0xe6, 0x10,       /* $e004: inc $10 */
+        0x8d, 0x00, 0xe0, /* sta $e000 */
+        0x40,             /* rti */
+    ];
+    prg[0x6000..0x6000 + code.len()].copy_from_slice(&code);
+    /* nmi $e009, reset $e000, irq $e004 */
+    prg[0x7ffa..].copy_from_slice(&[0x09, 0xe0, 0x00, 0xe0, 0x04, 0xe0]);
+    Cart {
+        prg,
+        chr: vec![0; 0x2000],
+        sram: vec![0; 0x2000],
+        nt_ram: Vec::new(),
+        mirror: MirrorType::Vertical,
+    }
+}
+
+fn set_irq(cpu: &mut CPU, reload: u8) {
+    cpu.mem.write_without_tick(0xc000, reload);
+    cpu.mem.write_without_tick(0xc001, 0); /* reload on the next clock */
+    cpu.mem.write_without_tick(0xe001, 0);
+}
+
+/* the scanline on which the IRQ handler runs, with rendering enabled in
+ * vblank using the given $2000 */
+fn irq_line(ppuctl: u8, reload: u8) -> Option<u16> {
+    let mut res = None;
+    run_cart(mmc3_cart(), 4, |cpu, _| {
+        run_frames(cpu, 3);
+        cpu.mem.write_without_tick(0x2000, ppuctl);
+        cpu.mem.write_without_tick(0x2001, 0x18);
+        set_irq(cpu, reload);
+        for _ in 0..30000 {
+            cpu.step();
+            if cpu.mem.peek(0x10) != 0 {
+                res = Some(cpu.mem.bus.get_ppu().get_state().scanline);
+                break
+            }
+        }
+    });
+    res
+}
+
+#[test]
+fn scanline_counter() {
+    /* sprites at $1000: one clock per line at the sprite fetches,
+     * starting with the pre-render line that reloads the counter */
+    assert_eq!(irq_line(0x08, 20), Some(19));
+    assert_eq!(irq_line(0x08, 1), Some(0));
+    /* 8x16 sprites take the table from the tile: the zeroed OAM puts all
+     * of them on lines 0..15 with tile 0 at $0000, the empty slots of the
+     * next lines fetch tile $ff at $1000 */
+    assert_eq!(irq_line(0x20, 20), Some(16 + 19));
+    /* background at $1000: the short lows between its fetches are filtered
+     * out, A12 rises once per line when the tiles of the next line are
+     * fetched after the sprites ($0000); the IRQ comes at the end of line
+     * 19 and is handled on line 20 */
+    assert_eq!(irq_line(0x10, 20), Some(20));
+    /* everything at $0000: A12 never rises */
+    assert_eq!(irq_line(0x00, 20), None);
+}
+
+/* toggle A12 through $2006 with rendering off, waiting `wait` CPU cycles
+ * with A12 low before each rise; returns the IRQs taken */
+fn toggle_a12(toggles: usize, wait: usize) -> u8 {
+    let mut res = 0;
+    run_cart(mmc3_cart(), 4, |cpu, _| {
+        run_frames(cpu, 3);
+        set_irq(cpu, 2);
+        for _ in 0..toggles {
+            for _ in 0..wait {
+                cpu.mem.bus.stall()
+            }
+            for &b in [0x10, 0x00, 0x00, 0x00].iter() {
+                cpu.mem.write_without_tick(0x2006, b)
+            }
+        }
+        for _ in 0..10 {
+            cpu.step()
+        }
+        res = cpu.mem.peek(0x10)
+    });
+    res
+}
+
+#[test]
+fn a12_from_ppuaddr() {
+    /* reload to 2, then 1, then 0 and IRQ */
+    assert_eq!(toggle_a12(2, 4), 0);
+    assert_eq!(toggle_a12(3, 4), 1);
+    /* too short a low: only the first rise is seen */
+    assert_eq!(toggle_a12(3, 0), 0);
+}
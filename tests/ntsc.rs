@@ -0,0 +1,86 @@
+use runes::ntsc::{
+    ntsc_width, NtscFilter, NTSC_HEIGHT, NTSC_IN_WIDTH, NTSC_WIDTH,
+};
+use runes::palette::NtscParams;
+use runes::ppu::Overscan;
+
+/* color bars that change every 8 pixels and every line, with emphasis in
+ * the lower half */
+fn bars(overscan: &Overscan) -> Vec<u16> {
+    let (w, h) = (overscan.width(), overscan.height());
+    (0..w * h)
+        .map(|i| {
+            let (x, y) = (i % w, i / w);
+            (((x / 8 + y) % 64) | ((y * 8 / h) << 6)) as u16
+        })
+        .collect()
+}
+
+fn filter(frame: &[u16], overscan: &Overscan, phase: u8) -> Vec<u32> {
+    let f = NtscFilter::new(&NtscParams::new());
+    /* one more line than needed, which must be left alone */
+    let mut out = vec![
+        0xdeadbeef;
+        ntsc_width(overscan.width()) * (overscan.height() + 1)
+    ];
+    f.filter(frame, overscan, phase, &mut out);
+    out
+}
+
+#[test]
+fn output_width() {
+    assert_eq!(ntsc_width(NTSC_IN_WIDTH), NTSC_WIDTH);
+    assert_eq!(ntsc_width(240), 548);
+    assert_eq!(ntsc_width(8), 18);
+
+    for overscan in [
+        Overscan::none(),
+        Overscan::ntsc(),
+        Overscan::new(8, 16, 8, 24).unwrap(),
+    ]
+    .iter()
+    {
+        let out = filter(&bars(overscan), overscan, 0);
+        let n = ntsc_width(overscan.width()) * overscan.height();
+        assert!(out[..n].iter().all(|&c| c <= 0xffffff));
+        assert!(out[n..].iter().all(|&c| c == 0xdeadbeef))
+    }
+    assert_eq!(Overscan::none().height(), NTSC_HEIGHT);
+}
+
+#[test]
+fn deterministic() {
+    let overscan = Overscan::none();
+    let frame = bars(&overscan);
+    let a = filter(&frame, &overscan, 0);
+    assert_eq!(a, filter(&frame, &overscan, 0));
+    /* the dot crawl moves with the starting phase, over three frames */
+    let b = filter(&frame, &overscan, 1);
+    assert_ne!(a, b);
+    assert_ne!(b, filter(&frame, &overscan, 2));
+    assert_eq!(a, filter(&frame, &overscan, 3));
+}
+
+#[test]
+fn flat_colors() {
+    let overscan = Overscan::ntsc();
+    let n = overscan.width() * overscan.height();
+    let flat = |idx: u16| filter(&vec![idx; n], &overscan, 1);
+    /* black stays black, a gray has no chroma and no dot crawl */
+    assert!(flat(0x0f)[..n].iter().all(|&c| c == 0));
+    let gray = flat(0x00);
+    let c = gray[0];
+    assert!(c & 0xff == (c >> 8) & 0xff && c & 0xff == c >> 16);
+    assert!(gray[..ntsc_width(overscan.width()) * overscan.height()]
+        .iter()
+        .all(|&p| p == c));
+    /* away from the edges, a flat red is red everywhere */
+    let w = ntsc_width(overscan.width());
+    let red = flat(0x16);
+    for y in 0..overscan.height() {
+        for &p in &red[y * w + 8..(y + 1) * w - 8] {
+            let (r, g, b) = (p >> 16, (p >> 8) & 0xff, p & 0xff);
+            assert!(r > g && r > b, "{:06x} at line {}", p, y)
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use runes::palette::{NtscParams, Palette, DEFAULT_COLORS, RP2C04_MAPS};
+
+/* the 64 base colors, without emphasis */
+fn base_colors(p: &Palette) -> Vec<u32> {
+    (0..64).map(|i| p.get(i)).collect()
+}
+
+#[test]
+fn default_colors() {
+    let p = Palette::new();
+    assert_eq!(base_colors(&p), DEFAULT_COLORS.to_vec());
+    /* emphasizing red dims green and blue by a quarter */
+    assert_eq!(p.get(0x40 | 0x20), 0xffbebf);
+}
+
+#[test]
+fn rgb_ppu() {
+    let p = Palette::from_rgb_ppu();
+    /* 0o014, 0o700, 0o777 and 0o000 in the 3-bit master palette */
+    assert_eq!(p.get(0x01), 0x002491);
+    assert_eq!(p.get(0x16), 0xff0000);
+    assert_eq!(p.get(0x20), 0xffffff);
+    assert_eq!(p.get(0x0f), 0x000000);
+    /* emphasis turns a channel fully on */
+    assert_eq!(p.get(0x40 | 0x0f), 0xff0000);
+    assert_eq!(p.get(0x80 | 0x0f), 0x00ff00);
+    assert_eq!(p.get(0x100 | 0x0f), 0x0000ff);
+    assert_eq!(p.get(0x1c0 | 0x01), 0xffffff);
+}
+
+#[test]
+fn rp2c04() {
+    assert!(Palette::from_rp2c04(0).is_none());
+    assert!(Palette::from_rp2c04(5).is_none());
+    let rgb = Palette::from_rgb_ppu();
+    let mut master = base_colors(&rgb);
+    master.sort_unstable();
+    master.dedup();
+    for (v, map) in RP2C04_MAPS.iter().enumerate() {
+        let p = Palette::from_rp2c04(v as u8 + 1).unwrap();
+        for i in 0..0x200 {
+            let j = (i & 0x1c0) | map[(i & 0x3f) as usize] as u16;
+            assert_eq!(p.get(i), rgb.get(j))
+        }
+        /* a scrambled order of the same master palette */
+        let mut colors = base_colors(&p);
+        colors.sort_unstable();
+        colors.dedup();
+        assert_eq!(colors, master, "RP2C04-000{}", v + 1)
+    }
+    /* 0o755 at index 0 of the RP2C04-0001 */
+    assert_eq!(Palette::from_rp2c04(1).unwrap().get(0), 0xffb6b6);
+}
+
+#[test]
+fn pal_file() {
+    let data: Vec<u8> = (0..192).map(|i| i as u8).collect();
+    let p = Palette::from_pal(&data).unwrap();
+    assert_eq!(p.get(0x00), 0x000102);
+    assert_eq!(p.get(0x3f), 0xbdbebf);
+    assert_eq!(p.get(0x40 | 0x3f), 0xbd8e8f);
+
+    let data: Vec<u8> = (0..1536).map(|i| (i / 3) as u8).collect();
+    let p = Palette::from_pal(&data).unwrap();
+    /* all the emphasis combinations are taken from the file */
+    assert_eq!(p.get(0x40), 0x404040);
+    assert_eq!(p.get(0x1ff), 0xffffff);
+
+    assert!(Palette::from_pal(&[]).is_none());
+    assert!(Palette::from_pal(&[0; 191]).is_none());
+    assert!(Palette::from_pal(&[0; 1535]).is_none());
+}
+
+#[test]
+fn ntsc() {
+    let params = NtscParams::new();
+    let p = Palette::from_ntsc(&params);
+    assert_eq!(base_colors(&p), base_colors(&Palette::from_ntsc(&params)));
+    /* the blacks and the whites */
+    assert_eq!(p.get(0x0f), 0x000000);
+    assert_eq!(p.get(0x0d), 0x000000);
+    let (r, g, b) = p.get_rgb(0x30);
+    assert!(r > 0xe0 && g > 0xe0 && b > 0xe0);
+    /* the grays have no chroma */
+    let (r, g, b) = p.get_rgb(0x00);
+    assert!(r == g && g == b && r > 0x30);
+    /* the hues of red, green and blue */
+    let (r, g, b) = p.get_rgb(0x16);
+    assert!(r > g && r > b);
+    let (r, g, b) = p.get_rgb(0x1a);
+    assert!(g > r && g > b);
+    let (r, g, b) = p.get_rgb(0x12);
+    assert!(b > r && b > g);
+    /* emphasis dims the other channels */
+    let (r, g, b) = p.get_rgb(0x30);
+    let (er, eg, eb) = p.get_rgb(0x40 | 0x30);
+    assert!(er >= eg && er >= eb && eg < g && eb < b && er <= r);
+}
@@ -0,0 +1,417 @@
+mod common;
+
+use common::{idle_cart, run_cart, run_frames, run_rom, Buf};
+use runes::mos6502::CPU;
+use runes::ppu::{
+    Overscan, NAMETABLE_VIEW_HEIGHT, NAMETABLE_VIEW_WIDTH, OAM_VIEW_HEIGHT,
+    OAM_VIEW_WIDTH, PALETTE_VIEW_HEIGHT, PALETTE_VIEW_WIDTH,
+    PATTERN_VIEW_HEIGHT, PATTERN_VIEW_WIDTH, PPU,
+};
+use runes::region::Region;
+
+fn write_ppu(cpu: &mut CPU, addr: u16, data: &[u8]) {
+    cpu.mem.write_without_tick(0x2006, (addr >> 8) as u8);
+    cpu.mem.write_without_tick(0x2006, addr as u8);
+    for &d in data {
+        cpu.mem.write_without_tick(0x2007, d)
+    }
+}
+
+/* put `n` sprites side by side on scanlines 101..108 and count the sprite
+ * pixels drawn on line 101, also returns the overflow flag */
+fn draw_sprites_on_a_line(n: usize, no_sprite_limit: bool) -> (usize, bool) {
+    let mut res = (0, false);
+    run_cart(idle_cart(), 0, |cpu, frame| {
+        /* past the warm-up, and in vblank */
+        run_frames(cpu, 3);
+        cpu.mem.bus.get_ppu().no_sprite_limit = no_sprite_limit;
+        cpu.mem.write_without_tick(0x2003, 0);
+        for i in 0..64 {
+            let sprite = if i < n {
+                [100, 1, 0, (i * 16) as u8]
+            } else {
+                [0xff; 4]
+            };
+            for &b in sprite.iter() {
+                cpu.mem.write_without_tick(0x2004, b)
+            }
+        }
+        write_ppu(cpu, 0x3f00, &[0x0f]);
+        write_ppu(cpu, 0x3f11, &[0x30]);
+        cpu.mem.write_without_tick(0x2001, 0x14); /* sprites, no clipping */
+        run_frames(cpu, 2);
+        let drawn = (0..256).filter(|&x| frame.pixel(x, 101) == 0x30).count();
+        let overflow = cpu.mem.bus.get_ppu().get_state().ppustatus & 0x20;
+        res = (drawn, overflow != 0)
+    });
+    res
+}
+
+#[test]
+fn sprite_limit() {
+    assert_eq!(draw_sprites_on_a_line(8, false), (64, false));
+    assert_eq!(draw_sprites_on_a_line(10, false), (64, true));
+}
+
+#[test]
+fn no_sprite_limit() {
+    assert_eq!(draw_sprites_on_a_line(8, true), (64, false));
+    /* all the sprites are drawn, overflow is still reported */
+    assert_eq!(draw_sprites_on_a_line(10, true), (80, true));
+}
+
+#[test]
+fn settings_are_not_saved() {
+    run_cart(idle_cart(), 0, |cpu, _| {
+        run_frames(cpu, 2);
+        let ppu = cpu.mem.bus.get_ppu();
+        ppu.no_sprite_limit = true;
+        ppu.skip_output = true;
+        ppu.oam_glitches = true;
+        let mut state = Buf::default();
+        assert!(ppu.save(&mut state));
+        ppu.no_sprite_limit = false;
+        ppu.skip_output = false;
+        ppu.oam_glitches = false;
+        assert!(ppu.load(&mut state));
+        assert_eq!(state.pos, state.data.len());
+        assert!(!ppu.no_sprite_limit && !ppu.skip_output && !ppu.oam_glitches)
+    })
+}
+
+#[test]
+fn overscan_bounds() {
+    let o = Overscan::new(8, 16, 4, 12).unwrap();
+    assert_eq!((o.top(), o.bottom(), o.left(), o.right()), (8, 16, 4, 12));
+    assert_eq!((o.width(), o.height()), (240, 216));
+    /* at least 8x8 pixels are left */
+    assert!(Overscan::new(116, 116, 124, 124).is_some());
+    assert!(Overscan::new(117, 116, 0, 0).is_none());
+    assert!(Overscan::new(0, 0, 124, 125).is_none());
+    assert!(Overscan::new(255, 255, 255, 255).is_none());
+}
+
+/* the whole machine state after running nestest for `frames` frames, with
+ * or without the picture */
+fn state_after_frames(frames: usize, skip_output: bool) -> Vec<u8> {
+    let mut res = Vec::new();
+    run_rom("testroms/nestest.nes", |cpu, frame| {
+        cpu.mem.bus.get_ppu().skip_output = skip_output;
+        run_frames(cpu, frames);
+        let mut state = Buf::default();
+        assert!(cpu.save(&mut state));
+        assert!(cpu.mem.bus.get_ppu().save(&mut state));
+        assert!(cpu.mem.bus.get_apu().save(&mut state));
+        /* the menu is up after 10 frames, unless the output is skipped */
+        let drawn = (0..256 * 240)
+            .any(|i| frame.pixel(i % 256, i / 256) != frame.pixel(0, 0));
+        assert_eq!(drawn, frames >= 10 && !skip_output);
+        res = state.data
+    });
+    res
+}
+
+#[test]
+fn skip_output_keeps_the_state() {
+    for &frames in [1, 10, 30].iter() {
+        /* no assert_eq, the states are too long to print */
+        assert!(
+            state_after_frames(frames, true) ==
+                state_after_frames(frames, false),
+            "{} frames",
+            frames
+        )
+    }
+}
+
+/* the dot whose tick raises the sprite 0 hit flag, with sprite 0 at `x` on
+ * an opaque background */
+fn sprite_zero_hit_dot(x: u8) -> Option<(u16, u16)> {
+    let mut res = None;
+    run_cart(idle_cart(), 0, |cpu, _| {
+        run_frames(cpu, 3);
+        write_ppu(cpu, 0x2000, &[1; 960]);
+        write_ppu(cpu, 0x3f00, &[0x0f, 0x16]);
+        cpu.mem.write_without_tick(0x2003, 0);
+        for &b in [49, 1, 0, x].iter() {
+            cpu.mem.write_without_tick(0x2004, b)
+        }
+        /* scroll to the top-left of $2000 */
+        cpu.mem.write_without_tick(0x2000, 0);
+        cpu.mem.write_without_tick(0x2005, 0);
+        cpu.mem.write_without_tick(0x2005, 0);
+        cpu.mem.write_without_tick(0x2001, 0x1e);
+        let bus = &cpu.mem.bus;
+        let ppu = bus.get_ppu();
+        for _ in 0..341 * 262 {
+            let st = ppu.get_state();
+            ppu.tick(bus);
+            if ppu.get_flag_sprite_zero() {
+                res = Some((st.scanline, st.cycle));
+                break
+            }
+        }
+    });
+    res
+}
+
+#[test]
+fn sprite_zero_hit_timing() {
+    /* pixel x is drawn at dot x + 1, the flag rises one dot later */
+    assert_eq!(sprite_zero_hit_dot(100), Some((50, 102)));
+    assert_eq!(sprite_zero_hit_dot(8), Some((50, 10)));
+    assert_eq!(sprite_zero_hit_dot(254), Some((50, 256)));
+    /* never at x = 255 */
+    assert_eq!(sprite_zero_hit_dot(255), None);
+}
+
+/* write $2004 in the middle of a rendered line, returns the first OAM byte
+ * and OAMADDR afterwards */
+fn write_oamdata_while_rendering(oam_glitches: bool) -> (u8, u8) {
+    let mut res = (0, 0);
+    run_cart(idle_cart(), 0, |cpu, _| {
+        run_frames(cpu, 3);
+        let ppu = cpu.mem.bus.get_ppu();
+        ppu.oam_glitches = oam_glitches;
+        cpu.mem.write_without_tick(0x2003, 0);
+        cpu.mem.write_without_tick(0x2004, 0x11);
+        cpu.mem.write_without_tick(0x2001, 0x18);
+        while ppu.get_state().scanline != 100 {
+            ppu.tick(&cpu.mem.bus);
+        }
+        cpu.mem.write_without_tick(0x2003, 0);
+        cpu.mem.write_without_tick(0x2004, 0x55);
+        let st = ppu.get_state();
+        res = (st.oam[0], st.oamaddr)
+    });
+    res
+}
+
+#[test]
+fn oamdata_write_while_rendering() {
+    assert_eq!(write_oamdata_while_rendering(false), (0x55, 1));
+    /* the glitch: no write, the sprite index is bumped */
+    assert_eq!(write_oamdata_while_rendering(true), (0x11, 4));
+}
+
+/* the first sprite after some frames with rendering on or off */
+fn oam_after_frames(region: Region, rendering: bool) -> [u8; 4] {
+    let mut res = [0; 4];
+    run_cart(idle_cart(), 0, |cpu, _| {
+        cpu.mem.bus.set_region(region);
+        cpu.mem.bus.get_ppu().oam_glitches = true;
+        run_frames(cpu, 3);
+        cpu.mem.write_without_tick(0x2003, 0);
+        for &b in [0x10, 0x20, 0x01, 0x40].iter() {
+            cpu.mem.write_without_tick(0x2004, b)
+        }
+        cpu.mem
+            .write_without_tick(0x2001, if rendering { 0x18 } else { 0 });
+        run_frames(cpu, 5);
+        cpu.mem.write_without_tick(0x2001, 0);
+        for (i, b) in res.iter_mut().enumerate() {
+            cpu.mem.write_without_tick(0x2003, i as u8);
+            *b = cpu.mem.read_without_tick(0x2004)
+        }
+    });
+    res
+}
+
+#[test]
+fn oam_decay() {
+    for &region in [Region::NTSC, Region::PAL, Region::Dendy].iter() {
+        /* the refresh by rendering outlasts a vblank of every region */
+        assert_eq!(oam_after_frames(region, true), [0x10, 0x20, 0x01, 0x40]);
+        assert_eq!(oam_after_frames(region, false), [0xff; 4]);
+    }
+}
+
+/* OAM after rendering is disabled on dot `dot` of scanline 100 and enabled
+ * again right away, with OAM filled with the byte numbers */
+fn oam_after_pause(dot: u16, oam_glitches: bool) -> Vec<u8> {
+    let mut res = Vec::new();
+    run_cart(idle_cart(), 0, |cpu, _| {
+        cpu.mem.bus.get_ppu().oam_glitches = oam_glitches;
+        run_frames(cpu, 3);
+        cpu.mem.write_without_tick(0x2003, 0);
+        for i in 0..=255u8 {
+            /* bits 2-4 of the attribute bytes do not exist */
+            cpu.mem.write_without_tick(0x2004, i & 0xe3)
+        }
+        cpu.mem.write_without_tick(0x2001, 0x18);
+        let ppu = cpu.mem.bus.get_ppu();
+        while (ppu.get_state().scanline, ppu.get_state().cycle) != (100, dot) {
+            ppu.tick(&cpu.mem.bus);
+        }
+        cpu.mem.write_without_tick(0x2001, 0);
+        cpu.mem.write_without_tick(0x2001, 0x18);
+        while ppu.get_state().scanline != 101 {
+            ppu.tick(&cpu.mem.bus);
+        }
+        res = ppu.get_state().oam.to_vec()
+    });
+    res
+}
+
+#[test]
+fn oam_corruption() {
+    let oam: Vec<u8> = (0..=255u8).map(|i| i & 0xe3).collect();
+    /* the row given by the secondary OAM address (while clearing it, while
+     * sprites 24..31 at y = 96 are copied to it, during the sprite fetches)
+     * is overwritten by row 0 */
+    for &(dot, row) in [(10, 4), (64, 31), (150, 18), (300, 23)].iter() {
+        let mut expected = oam.clone();
+        expected.copy_within(0..8, row * 8);
+        assert_eq!(oam_after_pause(dot, true), expected, "dot {}", dot)
+    }
+    /* no corruption outside of dots 1..320, or without the glitches */
+    assert_eq!(oam_after_pause(330, true), oam);
+    assert_eq!(oam_after_pause(10, false), oam);
+}
+
+/* OAM with sprites 0..7 on scanline 100 and the given sprites 8.. after
+ * them, the rest off the screen */
+fn oam_layout(extra: &[[u8; 4]]) -> [u8; 256] {
+    let mut oam = [0xff; 256];
+    for i in 0..8 {
+        oam[i * 4..i * 4 + 4].copy_from_slice(&[100, 1, 0, i as u8 * 8])
+    }
+    for (i, s) in extra.iter().enumerate() {
+        oam[32 + i * 4..36 + i * 4].copy_from_slice(s)
+    }
+    oam
+}
+
+/* run the evaluation of scanline 100, with OAM set up in vblank, until
+ * `dot` has been ticked; returns the overflow flag and $2004 */
+fn eval_line_100(oam: &[u8; 256], dot: u16) -> (bool, u8) {
+    let mut res = (false, 0);
+    run_cart(idle_cart(), 0, |cpu, _| {
+        run_frames(cpu, 3);
+        cpu.mem.write_without_tick(0x2003, 0);
+        for &b in oam.iter() {
+            cpu.mem.write_without_tick(0x2004, b)
+        }
+        cpu.mem.write_without_tick(0x2001, 0x18);
+        let bus = &cpu.mem.bus;
+        let ppu = bus.get_ppu();
+        loop {
+            let st = ppu.get_state();
+            ppu.tick(bus);
+            if (st.scanline, st.cycle) == (100, dot) {
+                break
+            }
+        }
+        let overflow = ppu.get_state().ppustatus & 0x20 != 0;
+        res = (overflow, cpu.mem.read_without_tick(0x2004))
+    });
+    res
+}
+
+#[test]
+fn sprite_overflow_scan() {
+    let off = [0xff; 4];
+    let overflow = |extra: &[[u8; 4]]| eval_line_100(&oam_layout(extra), 340).0;
+    assert!(!overflow(&[]));
+    assert!(overflow(&[[100, 1, 0, 0]]));
+    /* after a miss the scan moves on diagonally: sprite 9 is checked
+     * against its tile byte, then sprite 10 against its attributes, ... */
+    assert!(!overflow(&[off, [100, 0xff, 0xff, 0xff]]));
+    assert!(overflow(&[off, [0xff, 100, 0xff, 0xff]]));
+    assert!(overflow(&[off, off, [0xff, 0xff, 100, 0xff]]));
+    assert!(!overflow(&[off, off, [100, 0xff, 0xff, 0xff]]));
+}
+
+#[test]
+fn sprite_overflow_reads() {
+    /* a match on the tile byte of sprite 9 at dot 132 is followed by reads
+     * of the next 3 bytes of OAM, then the scan stops on sprite 10 */
+    let oam = oam_layout(&[
+        [0xff; 4],
+        [0xff, 100, 0xe2, 0xe3],
+        [0xe4, 0xe5, 0xe6, 0xe7],
+        [0xe8, 0xe9, 0xea, 0xeb],
+    ]);
+    let reads: Vec<_> = [131, 133, 135, 137, 139, 141]
+        .iter()
+        .map(|&d| eval_line_100(&oam, d))
+        .collect();
+    assert_eq!(
+        reads,
+        [
+            (false, 100),
+            (true, 0xe2),
+            (true, 0xe3),
+            (true, 0xe4),
+            (true, 0xe4),
+            (true, 0xe8)
+        ]
+    );
+}
+
+/* tile 1 at the top-left of $2000 (mirrored to $2400), sprite 0 with
+ * tile 1, and a palette of black, red and green */
+fn render_views(f: impl FnOnce(&PPU)) {
+    run_cart(idle_cart(), 0, |cpu, _| {
+        run_frames(cpu, 3);
+        write_ppu(cpu, 0x2000, &[1]);
+        write_ppu(cpu, 0x3f00, &[0x0f, 0x16]);
+        write_ppu(cpu, 0x3f11, &[0x2a]);
+        write_ppu(cpu, 0x0000, &[]); /* t = 0, scrolled to $2000 */
+        cpu.mem.write_without_tick(0x2003, 0);
+        for &b in [0, 1, 0, 0].iter() {
+            cpu.mem.write_without_tick(0x2004, b)
+        }
+        f(cpu.mem.bus.get_ppu())
+    })
+}
+
+#[test]
+fn debug_views() {
+    render_views(|ppu| {
+        let mut out = vec![0xffff; PATTERN_VIEW_WIDTH * PATTERN_VIEW_HEIGHT];
+        ppu.render_pattern_tables(0, &mut out);
+        let at = |x: usize, y: usize| out[y * PATTERN_VIEW_WIDTH + x];
+        assert_eq!((at(8, 0), at(15, 7), at(16, 0)), (0x16, 0x16, 0x0f));
+        assert_eq!(at(128 + 8, 0), 0x0f);
+        assert!(out.iter().all(|&c| c < 0x40));
+
+        let mut out =
+            vec![0xffff; NAMETABLE_VIEW_WIDTH * NAMETABLE_VIEW_HEIGHT];
+        ppu.render_nametables(None, &mut out);
+        let at = |x: usize, y: usize| out[y * NAMETABLE_VIEW_WIDTH + x];
+        assert_eq!((at(0, 0), at(7, 7), at(8, 8)), (0x16, 0x16, 0x0f));
+        assert_eq!((at(256, 0), at(0, 240)), (0x16, 0x0f));
+        assert!(out.iter().all(|&c| c < 0x40));
+        ppu.render_nametables(Some(0x40), &mut out);
+        let at = |x: usize, y: usize| out[y * NAMETABLE_VIEW_WIDTH + x];
+        assert_eq!(
+            (at(100, 0), at(100, 239), at(255, 100)),
+            (0x40, 0x40, 0x40)
+        );
+        assert_eq!(at(100, 100), 0x0f);
+
+        let mut out = vec![0xffff; OAM_VIEW_WIDTH * OAM_VIEW_HEIGHT];
+        ppu.render_oam(&mut out);
+        let at = |x: usize, y: usize| out[y * OAM_VIEW_WIDTH + x];
+        assert_eq!(
+            (at(0, 0), at(7, 7), at(0, 8), at(8, 0)),
+            (0x2a, 0x2a, 0x0f, 0x0f)
+        );
+        assert!(out.iter().all(|&c| c < 0x40));
+
+        let mut out = vec![0xffff; PALETTE_VIEW_WIDTH * PALETTE_VIEW_HEIGHT];
+        ppu.render_palette(&mut out);
+        assert_eq!(out[..2], [0x0f, 0x16]);
+        assert_eq!((out[0x10], out[0x11]), (0x0f, 0x2a))
+    })
+}
+
+#[test]
+#[should_panic(expected = "out.len()")]
+fn debug_view_too_small() {
+    render_views(|ppu| {
+        let mut out = vec![0; NAMETABLE_VIEW_WIDTH * NAMETABLE_VIEW_HEIGHT - 1];
+        ppu.render_nametables(None, &mut out)
+    })
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::{idle_cart, run_cart_with};
+use runes::utils::RamPattern;
+
+/* the internal RAM, the cartridge SRAM and OAM right after power-up */
+fn powerup_ram(pattern: RamPattern) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let mut res = Default::default();
+    run_cart_with(idle_cart(), 0, pattern, None, |cpu, _| {
+        let ram = (0..0x800).map(|a| cpu.mem.peek(a)).collect();
+        let sram = (0x6000..0x8000).map(|a| cpu.mem.peek(a)).collect();
+        let oam = cpu.mem.bus.get_ppu().get_state().oam.to_vec();
+        res = (ram, sram, oam)
+    });
+    res
+}
+
+#[test]
+fn fixed_patterns() {
+    let (ram, sram, oam) = powerup_ram(RamPattern::Zeros);
+    assert!(ram.iter().chain(&sram).chain(&oam).all(|&b| b == 0x00));
+    let (ram, sram, oam) = powerup_ram(RamPattern::Ones);
+    assert!(ram.iter().chain(&sram).chain(&oam).all(|&b| b == 0xff));
+    let (ram, sram, _) = powerup_ram(RamPattern::Fceux);
+    for mem in [ram, sram].iter() {
+        for (i, &b) in mem.iter().enumerate() {
+            assert_eq!(b, if i & 4 == 0 { 0x00 } else { 0xff }, "{:x}", i)
+        }
+    }
+}
+
+#[test]
+fn random_pattern() {
+    let a = powerup_ram(RamPattern::Random(1));
+    /* the same seed gives the same power-up state */
+    assert_eq!(a, powerup_ram(RamPattern::Random(1)));
+    assert_ne!(a, powerup_ram(RamPattern::Random(2)));
+    /* each region gets its own bytes, which are not all the same */
+    let (ram, sram, _) = a;
+    assert_ne!(ram[..0x800], sram[..0x800]);
+    assert!(ram.iter().any(|&b| b != ram[0]));
+}
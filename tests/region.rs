@@ -0,0 +1,63 @@
+mod common;
+
+use common::{idle_cart, run_cart};
+use runes::region::Region;
+
+/* the CPU cycles between the starts of `n + 1` vblanks, with rendering
+ * off; also checks that the NMI comes on the vblank line */
+fn frame_cycles(region: Region, n: usize) -> Vec<u32> {
+    let mut res = Vec::new();
+    run_cart(idle_cart(), 0, |cpu, _| {
+        cpu.mem.bus.set_region(region);
+        let mut vbl = false;
+        let mut last = None;
+        for i in 0..200000 * n as u32 {
+            let nmi = cpu.get_state().nmi_pending;
+            cpu.mem.bus.stall();
+            let st = cpu.mem.bus.get_ppu().get_state();
+            let now = st.ppustatus & 0x80 != 0;
+            if now && !vbl {
+                assert_eq!(st.scanline, region.vblank_line());
+                if let Some(l) = last {
+                    /* the CPU does not run, so the NMI stays pending */
+                    assert!(!res.is_empty() || !nmi);
+                    assert!(cpu.get_state().nmi_pending);
+                    res.push(i - l)
+                }
+                if res.len() == n {
+                    break
+                }
+                last = Some(i)
+            } else if vbl && !now {
+                /* after the warm-up, which ignores $2000 */
+                cpu.mem.write_without_tick(0x2000, 0x80) /* NMI on */
+            }
+            vbl = now
+        }
+    });
+    res
+}
+
+#[test]
+fn frame_length() {
+    /* 341 x 262 dots at 3 per cycle, without the skipped dot since
+     * rendering is off: 2/3 of a cycle left over on each frame */
+    assert_eq!(frame_cycles(Region::NTSC, 3), [29780, 29781, 29781]);
+    /* 341 x 312 dots at 3.2 per cycle */
+    assert_eq!(frame_cycles(Region::PAL, 2), [33247, 33248]);
+    /* 341 x 312 dots at 3 per cycle */
+    assert_eq!(frame_cycles(Region::Dendy, 2), [35464, 35464]);
+}
+
+#[test]
+fn vblank_line() {
+    assert_eq!(Region::NTSC.vblank_line(), 241);
+    assert_eq!(Region::PAL.vblank_line(), 241);
+    assert_eq!(Region::Dendy.vblank_line(), 291);
+    for region in [Region::NTSC, Region::PAL, Region::Dendy].iter() {
+        let cycles = frame_cycles(*region, 1)[0] as f32;
+        let dots = 341.0 * region.scanlines() as f32;
+        let (num, den) = region.ppu_ratio();
+        assert!((cycles - dots * den as f32 / num as f32).abs() < 1.0)
+    }
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::{idle_cart, run_cart};
+use runes::mos6502::CPU;
+use runes::search::{RamSearch, Relation, RAM_SIZE, SRAM_SIZE};
+
+/* the bytes the tests change, with their values before and after */
+const CHANGES: [(u16, u8, u8); 5] = [
+    (0x0010, 5, 6),
+    (0x0020, 5, 4),
+    (0x0030, 7, 7),
+    (0x0040, 0, 0xff),
+    (0x6000, 5, 8),
+];
+
+fn poke(cpu: &mut CPU, after: bool) {
+    for &(addr, before, now) in CHANGES.iter() {
+        cpu.mem
+            .write_without_tick(addr, if after { now } else { before })
+    }
+}
+
+/* the candidates left by filtering the changes with `rel` */
+fn search(rel: Relation) -> (usize, Vec<(u16, u8)>) {
+    let mut res = Default::default();
+    run_cart(idle_cart(), 0, |cpu, _| {
+        let mut s = RamSearch::new();
+        poke(cpu, false);
+        s.reset(&cpu.mem);
+        assert_eq!(s.get_count(), RAM_SIZE + SRAM_SIZE);
+        poke(cpu, true);
+        let count = s.filter(&cpu.mem, rel);
+        assert_eq!(count, s.get_count());
+        res = (count, s.candidates().take(8).collect())
+    });
+    res
+}
+
+fn addrs(rel: Relation) -> Vec<u16> {
+    let (count, cand) = search(rel);
+    assert_eq!(count, cand.len());
+    cand.iter().map(|c| c.0).collect()
+}
+
+#[test]
+fn filters() {
+    assert_eq!(addrs(Relation::Changed), [0x0010, 0x0020, 0x0040, 0x6000]);
+    assert_eq!(addrs(Relation::Greater), [0x0010, 0x0040, 0x6000]);
+    assert_eq!(addrs(Relation::Less), [0x0020]);
+    assert_eq!(addrs(Relation::IncreasedBy(1)), [0x0010]);
+    assert_eq!(addrs(Relation::IncreasedBy(3)), [0x6000]);
+    /* deltas wrap around */
+    assert_eq!(addrs(Relation::DecreasedBy(1)), [0x0020, 0x0040]);
+    assert_eq!(addrs(Relation::Value(7)), [0x0030]);
+    let (count, cand) = search(Relation::Unchanged);
+    assert_eq!(count, RAM_SIZE + SRAM_SIZE - 4);
+    /* reported with the value in the new snapshot */
+    assert!(cand.contains(&(0x0000, 0)));
+    assert!(!cand.contains(&(0x0010, 6)));
+}
+
+#[test]
+fn narrow_down() {
+    run_cart(idle_cart(), 0, |cpu, _| {
+        let mut s = RamSearch::new();
+        s.reset(&cpu.mem);
+        poke(cpu, true);
+        /* $0030 changes too, from the zeroed RAM */
+        assert_eq!(s.filter(&cpu.mem, Relation::Changed), 5);
+        /* compared against the snapshot of the last filter */
+        cpu.mem.write_without_tick(0x0010, 9);
+        assert_eq!(s.filter(&cpu.mem, Relation::IncreasedBy(3)), 1);
+        assert_eq!(s.candidates().collect::<Vec<_>>(), [(0x0010, 9)]);
+        /* a byte dropped once stays out */
+        cpu.mem.write_without_tick(0x0030, 12);
+        assert_eq!(s.filter(&cpu.mem, Relation::Changed), 0);
+        s.reset(&cpu.mem);
+        assert_eq!(s.get_count(), RAM_SIZE + SRAM_SIZE);
+    })
+}
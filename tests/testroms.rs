@@ -0,0 +1,63 @@
+mod common;
+
+use common::{blargg_result, idle_cart, run_cart, run_frames, run_rom};
+
+const VBL_NMI_TIMING: [&str; 7] = [
+    "1.frame_basics",
+    "2.vbl_timing",
+    "3.even_odd_frames",
+    "4.vbl_clear_timing",
+    "5.nmi_suppression",
+    "6.nmi_disable",
+    "7.nmi_timing",
+];
+
+const BRANCH_TIMING: [&str; 3] =
+    ["1.Branch_Basics", "2.Backward_Branch", "3.Forward_Branch"];
+
+#[test]
+fn vbl_nmi_timing() {
+    for name in VBL_NMI_TIMING.iter() {
+        let path = format!("testroms/vbl_nmi_timing/{}.nes", name);
+        assert_eq!(blargg_result(&path, 300), 1, "{}", name)
+    }
+}
+
+#[test]
+fn branch_timing() {
+    for name in BRANCH_TIMING.iter() {
+        let path = format!("testroms/branch_timing_tests/{}.nes", name);
+        assert_eq!(blargg_result(&path, 300), 1, "{}", name)
+    }
+}
+
+#[test]
+fn rerun_after_reset() {
+    run_rom("testroms/vbl_nmi_timing/2.vbl_timing.nes", |cpu, _| {
+        run_frames(cpu, 300);
+        assert_eq!(cpu.mem.peek(0xf8), 1);
+        cpu.mem.write_without_tick(0xf8, 0);
+        cpu.mem.bus.reset();
+        run_frames(cpu, 300);
+        assert_eq!(cpu.mem.peek(0xf8), 1)
+    })
+}
+
+#[test]
+fn reset_warmup() {
+    run_cart(idle_cart(), 0, |cpu, _| {
+        run_frames(cpu, 3);
+        cpu.mem.bus.reset();
+        let ppu = cpu.mem.bus.get_ppu();
+        /* ignored until the pre-render line */
+        cpu.mem.write_without_tick(0x2001, 0x18);
+        assert_eq!(ppu.get_state().ppumask, 0);
+        let mut pre_line = 0;
+        while ppu.get_state().scanline >= pre_line {
+            pre_line = ppu.get_state().scanline;
+            ppu.tick(&cpu.mem.bus);
+        }
+        cpu.mem.write_without_tick(0x2001, 0x18);
+        assert_eq!(ppu.get_state().ppumask, 0x18)
+    })
+}
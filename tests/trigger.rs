@@ -0,0 +1,164 @@
+mod common;
+
+use common::{idle_cart, run_cart, run_frames};
+use runes::mos6502::CPU;
+use runes::trigger::Trigger;
+
+fn set(cpu: &mut CPU, addr: u16, data: u8) {
+    cpu.mem.write_without_tick(addr, data)
+}
+
+/* evaluate the trigger once per step, after the step has written RAM */
+fn eval_steps(trigger: &str, steps: &[&[(u16, u8)]]) -> Vec<bool> {
+    let mut t = Trigger::parse(trigger).unwrap();
+    let mut res = Vec::new();
+    run_cart(idle_cart(), 0, |cpu, _| {
+        for writes in steps {
+            for &(addr, data) in writes.iter() {
+                set(cpu, addr, data)
+            }
+            res.push(t.eval(&cpu.mem))
+        }
+    });
+    res
+}
+
+#[test]
+fn parse() {
+    for s in [
+        "0xH0010=5",
+        "0xH0010==5",
+        " 0x0010>=h100 ",
+        "0x 0010!=0",
+        "0xM0010=1_0xT0010<1",
+        "0xL0010<=3_0xU0010>2",
+        "0xW0010=0_0xX0010=0",
+        "d0xH0010<0xH0010",
+        "R:0xH0011=1_0xH0010=5.3.",
+        "P:0xH0012=1_0xH0010=5(3)",
+        "0xH0010=1S0xH0011=1S0xH0012=1",
+    ]
+    .iter()
+    {
+        assert!(Trigger::parse(s).is_some(), "{}", s)
+    }
+    for s in [
+        "",
+        "0xH0010",
+        "0xH0010=",
+        "0xH10000=1",
+        "0xHg=1",
+        "d5=5",
+        "Q:0xH0010=1",
+        "0xH0010=5.3",
+        "0xH0010=5(3",
+        "0xH0010!5",
+        "0xH0010=5_",
+        "0xH0010=5 junk",
+    ]
+    .iter()
+    {
+        assert!(Trigger::parse(s).is_none(), "{}", s)
+    }
+    let many = ["0xH0010=1"; 33].join("_");
+    assert!(Trigger::parse(&many).is_none());
+    let groups = ["0xH0010=1"; 9].join("S");
+    assert!(Trigger::parse(&groups).is_none());
+}
+
+#[test]
+fn sizes() {
+    let bytes: &[(u16, u8)] =
+        &[(0x10, 0x01), (0x11, 0x82), (0x12, 0x03), (0x13, 0x04)];
+    for s in [
+        "0xH0011=h82",
+        "0x0010=h8201",
+        "0xW0010=h038201",
+        "0xX0010=h04038201",
+        "0xM0010=1_0xN0010=0",
+        "0xS0011=0_0xT0011=1",
+        "0xL0011=2_0xU0011=8",
+        "0xH0011>=130_0xH0011<=130_0xH0011>129_0xH0011<131",
+        "0xH0010!=0xH0011",
+    ]
+    .iter()
+    {
+        assert_eq!(eval_steps(s, &[bytes]), [true], "{}", s)
+    }
+    assert_eq!(eval_steps("0xH0011<130", &[bytes]), [false]);
+}
+
+#[test]
+fn hit_counts() {
+    let one: &[(u16, u8)] = &[(0x10, 1)];
+    let zero: &[(u16, u8)] = &[(0x10, 0)];
+    /* counted while true, and kept once reached */
+    assert_eq!(
+        eval_steps("0xH0010=1.3.", &[one, zero, one, one, zero]),
+        [false, false, false, true, true]
+    );
+}
+
+#[test]
+fn reset_and_pause() {
+    let one: &[(u16, u8)] = &[(0x10, 1)];
+    let reset: &[(u16, u8)] = &[(0x20, 1)];
+    let clear: &[(u16, u8)] = &[(0x20, 0)];
+    assert_eq!(
+        eval_steps("R:0xH0020=1_0xH0010=1.2.", &[one, reset, clear, one]),
+        [false, false, false, true]
+    );
+    /* no hits are counted while paused */
+    assert_eq!(
+        eval_steps("P:0xH0020=1_0xH0010=1.2.", &[one, reset, one, clear]),
+        [false, false, false, true]
+    );
+}
+
+#[test]
+fn alternative_groups() {
+    let steps: &[&[(u16, u8)]] = &[
+        &[(0x10, 1)],
+        &[(0x12, 1)],
+        &[(0x10, 0)],
+        &[(0x10, 1), (0x12, 0)],
+    ];
+    assert_eq!(
+        eval_steps("0xH0010=1S0xH0011=1S0xH0012=1", steps),
+        [false, true, false, false]
+    );
+}
+
+#[test]
+fn deltas() {
+    let steps: &[&[(u16, u8)]] =
+        &[&[(0x10, 5)], &[(0x10, 6)], &[], &[(0x10, 4)], &[(0x10, 9)]];
+    /* nothing to compare against on the first frame */
+    assert_eq!(
+        eval_steps("d0xH0010<0xH0010", steps),
+        [false, true, false, false, true]
+    );
+    assert_eq!(
+        eval_steps("d0xH0010=6_0xH0010=4", steps),
+        [false, false, false, true, false]
+    );
+}
+
+#[test]
+fn evaluated_per_frame() {
+    run_cart(idle_cart(), 0, |cpu, _| {
+        cpu.mem.set_trigger(Trigger::parse("0xH0010=1.2."));
+        set(cpu, 0x10, 1);
+        run_frames(cpu, 1);
+        assert_eq!(cpu.mem.get_trigger_met(), None);
+        run_frames(cpu, 1);
+        let frame = cpu.mem.bus.get_ppu().frame;
+        assert_eq!(cpu.mem.get_trigger_met(), Some(frame));
+        /* not evaluated again once met */
+        set(cpu, 0x10, 0);
+        run_frames(cpu, 1);
+        assert_eq!(cpu.mem.get_trigger_met(), Some(frame));
+        cpu.mem.set_trigger(None);
+        assert_eq!(cpu.mem.get_trigger_met(), None);
+    })
+}